@@ -1,3 +1,4 @@
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::env;
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -9,17 +10,21 @@ use atty::Stream;
 use clap::ArgMatches;
 use clipboard::{ClipboardContext, ClipboardProvider};
 use daemonize::Daemonize;
-use dialoguer::Confirm;
+use dialoguer::{Confirm, Input, Password};
 use nix::unistd::{fork, ForkResult};
-use secrecy::{ExposeSecret, Secret};
+use secrecy::{ExposeSecret, SecretString};
 
 use crate::kbs2::agent;
-use crate::kbs2::backend::{self, Backend};
+use crate::kbs2::backend;
 use crate::kbs2::config::{self, Pinentry};
 use crate::kbs2::generator::Generator;
 use crate::kbs2::input;
+use crate::kbs2::label::Label;
+use crate::kbs2::lock::{self, LockKind, StoreLock};
 use crate::kbs2::record::{self, FieldKind::*, RecordBody};
 use crate::kbs2::session::Session;
+use crate::kbs2::ssh;
+use crate::kbs2::totp;
 use crate::kbs2::util;
 
 /// Implements the `kbs2 init` command.
@@ -41,12 +46,12 @@ pub fn init(matches: &ArgMatches, config_dir: &Path) -> Result<()> {
     }
 
     let password = if !matches.is_present("insecure-not-wrapped") {
-        Some(util::get_password(None, &Pinentry::default())?)
+        Some(util::get_password(None, Pinentry::default())?)
     } else {
         None
     };
 
-    config::initialize(&config_dir, &store_dir, password)
+    config::initialize(config_dir, store_dir, password)
 }
 
 /// Implements the `kbs2 agent` command (and subcommands).
@@ -54,7 +59,7 @@ pub fn agent(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("agent subcommand dispatch");
 
     if matches.subcommand().is_none() {
-        let mut agent = agent::Agent::new()?;
+        let mut agent = agent::Agent::new(config)?;
         if !matches.is_present("foreground") {
             Daemonize::new().start()?;
         }
@@ -65,7 +70,10 @@ pub fn agent(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     // No subcommand: run the agent itself
     match matches.subcommand() {
         Some(("flush", matches)) => agent_flush(matches),
+        Some(("log", matches)) => agent_log(matches),
         Some(("query", matches)) => agent_query(matches, config),
+        Some(("ssh-add", matches)) => agent_ssh_add(matches, config),
+        Some(("status", matches)) => agent_status(matches),
         Some(("unwrap", matches)) => agent_unwrap(matches, config),
         _ => unreachable!(),
     }
@@ -85,6 +93,24 @@ fn agent_flush(matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
+/// Implements the `kbs2 agent log` subcommand.
+fn agent_log(_matches: &ArgMatches) -> Result<()> {
+    log::debug!("dumping the agent's audit log");
+
+    for entry in agent::read_log(&agent::Agent::log_path())? {
+        println!(
+            "{} {} {} {} {}",
+            entry.timestamp,
+            entry.pid.map_or_else(|| "-".into(), |pid| pid.to_string()),
+            entry.exe.as_deref().unwrap_or("-"),
+            entry.request,
+            entry.outcome
+        );
+    }
+
+    Ok(())
+}
+
 /// Implements the `kbs2 agent query` subcommand.
 fn agent_query(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("querying the agent for a key's existence");
@@ -106,8 +132,61 @@ fn agent_query(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
     Ok(())
 }
 
+/// Implements the `kbs2 agent ssh-add` subcommand.
+fn agent_ssh_add(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("asking the agent to serve SSH keys");
+
+    if !config.wrapped {
+        return Err(anyhow!(
+            "config specifies a bare key; the agent can only serve SSH keys for wrapped keys"
+        ));
+    }
+
+    // NOTE(ww): Loading a session makes sure that the agent has the config's key.
+    let session: Session = config.try_into()?;
+    let client = agent::Client::new()?;
+
+    #[allow(clippy::unwrap_used)]
+    for label in matches.values_of("label").unwrap() {
+        let label = Label::new(label)?;
+        client.add_ssh_key(
+            &config.public_key,
+            label.as_str(),
+            &session.read_record(&label)?,
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 agent status` subcommand.
+fn agent_status(matches: &ArgMatches) -> Result<()> {
+    log::debug!("listing the agent's keys");
+
+    let client = agent::Client::new()?;
+    let keys = client.list_keys()?;
+
+    if matches.is_present("json") {
+        println!("{}", serde_json::to_string(&keys)?);
+        return Ok(());
+    }
+
+    for key in keys {
+        println!("{}", key.pubkey);
+        println!("\tkeyfile: {}", key.keyfile);
+        println!("\tunwrapped at: {}", key.unwrapped_at);
+        println!("\tlast used: {}", key.last_used);
+        match key.expires_in {
+            Some(expires_in) => println!("\texpires in: {}s", expires_in),
+            None => println!("\texpires in: never"),
+        }
+    }
+
+    Ok(())
+}
+
 /// Implements the `kbs2 agent unwrap` subcommand.
-fn agent_unwrap(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+fn agent_unwrap(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("asking the agent to unwrap a key");
 
     // Bare keys are loaded directly from their `keyfile`.
@@ -117,12 +196,42 @@ fn agent_unwrap(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     let client = agent::Client::new()?;
     if client.query_key(&config.public_key)? {
+        // NOTE(ww): The agent doesn't change how it holds a key once it's unwrapped, so
+        // asking for different options would otherwise be silently ignored.
+        if ["ttl", "idle-timeout", "confirm"]
+            .iter()
+            .any(|o| matches.is_present(o))
+        {
+            return Err(anyhow!(
+                "kbs2 agent already has this key; run `kbs2 agent flush` and unwrap it again \
+                 to change its options"
+            ));
+        }
+
         println!("kbs2 agent already has this key; ignoring.");
         return Ok(());
     }
 
+    let mut options = config.agent_key_options();
+    if let Some(ttl) = matches.value_of("ttl") {
+        options.ttl = Some(
+            ttl.parse()
+                .map_err(|_| anyhow!("invalid TTL: expected a number of seconds"))?,
+        );
+    }
+    if let Some(idle_timeout) = matches.value_of("idle-timeout") {
+        options.idle_timeout = Some(
+            idle_timeout
+                .parse()
+                .map_err(|_| anyhow!("invalid idle timeout: expected a number of seconds"))?,
+        );
+    }
+    if matches.is_present("confirm") {
+        options.confirm = true;
+    }
+
     let password = util::get_password(None, &config.pinentry)?;
-    client.add_key(&config.public_key, &config.keyfile, password)?;
+    client.add_key(&config.public_key, &config.keyfile, password, options)?;
 
     Ok(())
 }
@@ -139,8 +248,8 @@ pub fn new(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     }
 
     #[allow(clippy::unwrap_used)]
-    let label = matches.value_of("label").unwrap();
-    if session.has_record(label) && !matches.is_present("force") {
+    let label: Label = matches.value_of("label").unwrap().parse()?;
+    if session.has_record(&label) && !matches.is_present("force") {
         return Err(anyhow!("refusing to overwrite a record without --force"));
     }
 
@@ -160,70 +269,153 @@ pub fn new(matches: &ArgMatches, config: &config::Config) -> Result<()> {
         None
     };
 
-    // TODO: new_* below is a little silly. This should be de-duped.
     #[allow(clippy::unwrap_used)]
-    match matches.value_of("kind").unwrap() {
-        "login" => new_login(label, terse, &session, generator)?,
-        "environment" => new_environment(label, terse, &session, generator)?,
-        "unstructured" => new_unstructured(label, terse, &session, generator)?,
-        _ => unreachable!(),
-    }
+    let kind = matches.value_of("kind").unwrap();
+    let record = match kind {
+        "login" => {
+            let fields = input::fields(
+                &[Insensitive("Username"), Sensitive("Password")],
+                terse,
+                session.config,
+                generator,
+            )?;
+            record::Record::login(label.as_str(), &fields[0], &fields[1])
+        }
+        "environment" => {
+            let fields = input::fields(
+                &[Insensitive("Variable"), Sensitive("Value")],
+                terse,
+                session.config,
+                generator,
+            )?;
+            record::Record::environment(label.as_str(), &fields[0], &fields[1])
+        }
+        "unstructured" => {
+            let fields =
+                input::fields(&[Insensitive("Contents")], terse, session.config, generator)?;
+            record::Record::unstructured(label.as_str(), &fields[0])
+        }
+        "totp" => new_totp(label.as_str(), terse, &session, generator)?,
+        "ssh-key" => new_ssh_key(label.as_str(), terse, &session, generator)?,
+        kind => {
+            let kind = session
+                .config
+                .get_kind(kind)
+                .ok_or_else(|| anyhow!("unknown record kind: {}", kind))?;
+            let fields = input::fields(&kind.field_kinds(), terse, session.config, generator)?;
+            let values = kind
+                .fields
+                .iter()
+                .map(|f| f.name.clone())
+                .zip(fields)
+                .collect();
+
+            record::Record::custom(label.as_str(), &kind.name, values)
+        }
+    };
+
+    session.add_record(&record)?;
 
     if let Some(post_hook) = &session.config.commands.new.post_hook {
         log::debug!("post-hook: {}", post_hook);
-        session.config.call_hook(post_hook, &[label])?;
+        session.config.call_hook(post_hook, &[label.as_str()])?;
     }
 
     Ok(())
 }
 
 #[doc(hidden)]
-fn new_login(
+fn new_totp(
     label: &str,
     terse: bool,
     session: &Session,
     generator: Option<&dyn Generator>,
-) -> Result<()> {
+) -> Result<record::Record> {
+    // TOTP secrets are issued by the service, so generating one makes no sense.
+    if generator.is_some() {
+        return Err(anyhow!("TOTP secrets can't be generated"));
+    }
+
     let fields = input::fields(
-        &[Insensitive("Username"), Sensitive("Password")],
+        &[
+            Insensitive("Issuer"),
+            Insensitive("Account"),
+            Sensitive("Secret"),
+        ],
         terse,
         session.config,
-        generator,
+        None,
     )?;
-    let record = record::Record::login(label, &fields[0], &fields[1]);
+    let (issuer, account, secret) = (&fields[0], &fields[1], &fields[2]);
+
+    // The secret can be either a bare base32 secret or an `otpauth://` URI, in which
+    // case the URI supplies any parameters not explicitly given above.
+    let totp = if secret.starts_with("otpauth://") {
+        let mut totp = totp::parse_uri(secret)?;
+        if !issuer.is_empty() {
+            totp.issuer = issuer.into();
+        }
+        if !account.is_empty() {
+            totp.account = account.into();
+        }
+        totp
+    } else {
+        record::TotpFields {
+            secret: secret.into(),
+            issuer: issuer.into(),
+            account: account.into(),
+            ..Default::default()
+        }
+    };
+
+    // Make sure that the secret is actually usable before saving it.
+    totp::code(&totp, util::current_timestamp())?;
 
-    session.add_record(&record)
+    Ok(record::Record::totp(label, totp))
 }
 
-#[doc(hidden)]
-fn new_environment(
+/// Gathers the private key for a new SSH key record: from stdin in terse mode, and from
+/// a key file otherwise.
+fn new_ssh_key(
     label: &str,
     terse: bool,
     session: &Session,
     generator: Option<&dyn Generator>,
-) -> Result<()> {
-    let fields = input::fields(
-        &[Insensitive("Variable"), Sensitive("Value")],
-        terse,
-        session.config,
-        generator,
-    )?;
-    let record = record::Record::environment(label, &fields[0], &fields[1]);
+) -> Result<record::Record> {
+    if generator.is_some() {
+        return Err(anyhow!("SSH keys can't be generated"));
+    }
 
-    session.add_record(&record)
-}
+    let private_key = if terse {
+        input::fields(&[Sensitive("Private key")], terse, session.config, None)?.remove(0)
+    } else {
+        let key_file = Input::<String>::new()
+            .with_prompt("Private key file")
+            .interact()?;
+        std::fs::read_to_string(shellexpand::tilde(&key_file).as_ref())?
+    };
 
-#[doc(hidden)]
-fn new_unstructured(
-    label: &str,
-    terse: bool,
-    session: &Session,
-    generator: Option<&dyn Generator>,
-) -> Result<()> {
-    let fields = input::fields(&[Insensitive("Contents")], terse, session.config, generator)?;
-    let record = record::Record::unstructured(label, &fields[0]);
+    // NOTE(ww): The record itself is encrypted, so a passphrase would only get in the
+    // way of the agent signing with the key.
+    let private_key = if ssh::is_passphrase_protected(&private_key)? {
+        if terse {
+            return Err(anyhow!(
+                "passphrase-protected SSH keys can only be added interactively"
+            ));
+        }
 
-    session.add_record(&record)
+        let passphrase = Password::new().with_prompt("Passphrase").interact()?;
+        ssh::decrypt_private_key(&private_key, SecretString::new(passphrase))?
+            .expose_secret()
+            .clone()
+    } else {
+        private_key
+    };
+
+    // Make sure that the agent can actually sign with the key before saving it.
+    ssh::SigningKey::from_openssh(&private_key)?;
+
+    Ok(record::Record::ssh_key(label, &private_key))
 }
 
 /// Implements the `kbs2 list` command.
@@ -234,7 +426,25 @@ pub fn list(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     let (details, filter_kind) = (matches.is_present("details"), matches.is_present("kind"));
 
+    if let Some(kind) = matches.value_of("kind") {
+        if !session.config.has_kind(kind) {
+            return Err(anyhow!("unknown record kind: {}", kind));
+        }
+    }
+
+    // A folder like `work/` (or just `work`) lists only the records within it.
+    let folder = matches
+        .value_of("folder")
+        .map(|f| format!("{}/", f.trim_end_matches('/')));
+
+    let mut labels = vec![];
     for label in session.record_labels()? {
+        if let Some(folder) = &folder {
+            if !label.as_str().starts_with(folder.as_str()) {
+                continue;
+            }
+        }
+
         let mut display = String::new();
 
         if details || filter_kind {
@@ -248,21 +458,63 @@ pub fn list(matches: &ArgMatches, config: &config::Config) -> Result<()> {
                 }
             }
 
-            display.push_str(&label);
+            display.push_str(label.as_str());
 
             if details {
                 display.push_str(&format!(" {} {}", record.body, record.timestamp));
             }
         } else {
-            display.push_str(&label);
+            display.push_str(label.as_str());
         }
 
-        println!("{}", display);
+        labels.push(display);
+    }
+
+    if matches.is_present("tree") {
+        let mut tree = LabelTree::default();
+        for label in &labels {
+            let label = match &folder {
+                Some(folder) => &label[folder.len()..],
+                None => label,
+            };
+            tree.insert(label);
+        }
+
+        if let Some(folder) = &folder {
+            println!("{}", folder);
+        }
+        tree.print("");
+    } else {
+        for label in labels {
+            println!("{}", label);
+        }
     }
 
     Ok(())
 }
 
+/// A tree of record labels, split on their folders. Used by `kbs2 list --tree`.
+#[doc(hidden)]
+#[derive(Default)]
+struct LabelTree(BTreeMap<String, LabelTree>);
+
+impl LabelTree {
+    fn insert(&mut self, label: &str) {
+        let mut node = self;
+        for component in label.split('/') {
+            node = node.0.entry(component.into()).or_default();
+        }
+    }
+
+    fn print(&self, indent: &str) {
+        for (idx, (name, child)) in self.0.iter().enumerate() {
+            let last = idx == self.0.len() - 1;
+            println!("{}{}{}", indent, if last { "└── " } else { "├── " }, name);
+            child.print(&format!("{}{}", indent, if last { "    " } else { "│   " }));
+        }
+    }
+}
+
 /// Implements the `kbs2 rm` command.
 pub fn rm(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("removing a record");
@@ -270,7 +522,11 @@ pub fn rm(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     let session: Session = config.try_into()?;
 
     #[allow(clippy::unwrap_used)]
-    let labels: Vec<_> = matches.values_of("label").unwrap().collect();
+    let labels = matches
+        .values_of("label")
+        .unwrap()
+        .map(Label::new)
+        .collect::<Result<Vec<_>>>()?;
 
     for label in &labels {
         session.delete_record(label)?;
@@ -278,12 +534,54 @@ pub fn rm(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     if let Some(post_hook) = &session.config.commands.rm.post_hook {
         log::debug!("post-hook: {}", post_hook);
+        let labels: Vec<_> = labels.iter().map(Label::as_str).collect();
         session.config.call_hook(post_hook, &labels)?;
     }
 
     Ok(())
 }
 
+/// Implements the `kbs2 history` command.
+pub fn history(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("listing a record's history");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let label: Label = matches.value_of("label").unwrap().parse()?;
+
+    let revisions = session.record_revisions(&label)?;
+    if revisions.is_empty() && !session.has_record(&label) {
+        return Err(anyhow!("no such record: {}", label));
+    }
+
+    for revision in revisions {
+        let record = session.get_revision(&label, revision)?;
+        println!("{} {}", revision, record.timestamp);
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 restore` command.
+pub fn restore(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("restoring a record's prior revision");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let label: Label = matches.value_of("label").unwrap().parse()?;
+
+    #[allow(clippy::unwrap_used)]
+    let revision = matches
+        .value_of("rev")
+        .unwrap()
+        .parse::<u64>()
+        .map_err(|_| anyhow!("invalid revision: expected a revision number"))?;
+
+    session.restore_record(&label, revision)
+}
+
 /// Implements the `kbs2 dump` command.
 pub fn dump(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("dumping a record");
@@ -291,10 +589,17 @@ pub fn dump(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     let session: Session = config.try_into()?;
 
     #[allow(clippy::unwrap_used)]
-    let labels: Vec<_> = matches.values_of("label").unwrap().collect();
+    let labels = matches
+        .values_of("label")
+        .unwrap()
+        .map(Label::new)
+        .collect::<Result<Vec<_>>>()?;
+
+    // NOTE(ww): JSON output is meant for other programs, so it's never masked.
+    let reveal = matches.is_present("reveal");
 
     for label in labels {
-        let record = session.get_record(label)?;
+        let record = session.get_record(&label)?;
 
         if matches.is_present("json") {
             println!("{}", serde_json::to_string(&record)?);
@@ -309,6 +614,44 @@ pub fn dump(matches: &ArgMatches, config: &config::Config) -> Result<()> {
                     println!("Variable {}\nValue {}", e.variable, e.value)
                 }
                 RecordBody::Unstructured(u) => println!("Contents {}", u.contents),
+                RecordBody::Totp(t) => println!(
+                    "Issuer {}\nAccount {}\nSecret {}\nAlgorithm {}\nDigits {}\nPeriod {}",
+                    t.issuer, t.account, t.secret, t.algorithm, t.digits, t.period
+                ),
+                RecordBody::SshKey(s) => println!("Private key\n{}", s.private_key.trim_end()),
+                RecordBody::Custom(c) => {
+                    // Print fields in the order that the config declares them, if the
+                    // kind is still declared; any leftovers follow in sorted order.
+                    let mut values = c.values;
+                    if let Some(kind) = session.config.get_kind(&c.kind) {
+                        for field in &kind.fields {
+                            if let Some(value) = values.remove(&field.name) {
+                                println!("{} {}", field.name, value);
+                            }
+                        }
+                    }
+                    for (name, value) in values {
+                        println!("{} {}", name, value);
+                    }
+                }
+            }
+
+            let metadata = record.metadata;
+            if metadata.is_empty() {
+                continue;
+            }
+
+            if !metadata.tags.is_empty() {
+                println!("Tags {}", metadata.tags.join(", "));
+            }
+            for url in metadata.urls {
+                println!("URL {}", url);
+            }
+            if !metadata.notes.is_empty() {
+                println!("Notes {}", metadata.notes);
+            }
+            for (name, field) in &metadata.fields {
+                println!("Field {} {}", name, field.display_value(reveal));
             }
         }
     }
@@ -328,8 +671,8 @@ pub fn pass(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     }
 
     #[allow(clippy::unwrap_used)]
-    let label = matches.value_of("label").unwrap();
-    let record = session.get_record(label)?;
+    let label: Label = matches.value_of("label").unwrap().parse()?;
+    let record = session.get_record(&label)?;
 
     let login = match record.body {
         RecordBody::Login(l) => l,
@@ -338,38 +681,7 @@ pub fn pass(matches: &ArgMatches, config: &config::Config) -> Result<()> {
 
     let password = login.password;
     if matches.is_present("clipboard") {
-        // NOTE(ww): fork() is unsafe in multithreaded programs where the child calls
-        // non async-signal-safe functions. kbs2 is single threaded, so this usage is fine.
-        unsafe {
-            match fork() {
-                Ok(ForkResult::Child) => {
-                    // NOTE(ww): More dumbness: cfg! gets expanded into a boolean literal,
-                    // so it can't be used to conditionally compile code that only exists on
-                    // one platform.
-                    #[cfg(target_os = "linux")]
-                    {
-                        match session.config.commands.pass.x11_clipboard {
-                            // NOTE(ww): Why, might you ask, is clip_primary its own function?
-                            // It's because the clipboard crate has a bad abstraction:
-                            // ClipboardContext is the top-level type, but it's aliased to
-                            // X11Clipboard<Clipboard>. That means we can't produce it on a match.
-                            // The other option would be to create a ClipboardProvider trait object,
-                            // but it doesn't implement Sized. So we have to do things the dumb
-                            // way here. Alternatively, I could just be missing something obvious.
-                            config::X11Clipboard::Primary => clip_primary(password, &session)?,
-                            config::X11Clipboard::Clipboard => clip(password, &session)?,
-                        };
-                    }
-
-                    #[cfg(target_os = "macos")]
-                    {
-                        clip(password, &session)?;
-                    }
-                }
-                Err(_) => return Err(anyhow!("clipboard fork failed")),
-                _ => {}
-            }
-        }
+        clipboard(password, &session)?;
     } else if atty::isnt(Stream::Stdout) {
         print!("{}", password);
     } else {
@@ -384,6 +696,46 @@ pub fn pass(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     Ok(())
 }
 
+/// Copies the given secret to the clipboard from a forked child process, which
+/// clears it again according to the `commands.pass` settings.
+#[doc(hidden)]
+fn clipboard(secret: String, session: &Session) -> Result<()> {
+    // NOTE(ww): fork() is unsafe in multithreaded programs where the child calls
+    // non async-signal-safe functions. kbs2 is single threaded, so this usage is fine.
+    unsafe {
+        match fork() {
+            Ok(ForkResult::Child) => {
+                // NOTE(ww): More dumbness: cfg! gets expanded into a boolean literal,
+                // so it can't be used to conditionally compile code that only exists on
+                // one platform.
+                #[cfg(target_os = "linux")]
+                {
+                    match session.config.commands.pass.x11_clipboard {
+                        // NOTE(ww): Why, might you ask, is clip_primary its own function?
+                        // It's because the clipboard crate has a bad abstraction:
+                        // ClipboardContext is the top-level type, but it's aliased to
+                        // X11Clipboard<Clipboard>. That means we can't produce it on a match.
+                        // The other option would be to create a ClipboardProvider trait object,
+                        // but it doesn't implement Sized. So we have to do things the dumb
+                        // way here. Alternatively, I could just be missing something obvious.
+                        config::X11Clipboard::Primary => clip_primary(secret, session)?,
+                        config::X11Clipboard::Clipboard => clip(secret, session)?,
+                    };
+                }
+
+                #[cfg(target_os = "macos")]
+                {
+                    clip(secret, session)?;
+                }
+            }
+            Err(_) => return Err(anyhow!("clipboard fork failed")),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
 #[doc(hidden)]
 fn clip(password: String, session: &Session) -> Result<()> {
     let clipboard_duration = session.config.commands.pass.clipboard_duration;
@@ -437,6 +789,33 @@ fn clip_primary(password: String, session: &Session) -> Result<()> {
     Ok(())
 }
 
+/// Implements the `kbs2 otp` command.
+pub fn otp(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("getting a TOTP code");
+
+    let session: Session = config.try_into()?;
+
+    #[allow(clippy::unwrap_used)]
+    let label: Label = matches.value_of("label").unwrap().parse()?;
+    let record = session.get_record(&label)?;
+
+    let totp = match record.body {
+        RecordBody::Totp(t) => t,
+        _ => return Err(anyhow!("not a TOTP record: {}", label)),
+    };
+
+    let code = totp::code(&totp, util::current_timestamp())?;
+    if matches.is_present("clipboard") {
+        clipboard(code, &session)?;
+    } else if atty::isnt(Stream::Stdout) {
+        print!("{}", code);
+    } else {
+        println!("{}", code);
+    }
+
+    Ok(())
+}
+
 /// Implements the `kbs2 env` command.
 pub fn env(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("getting a environment variable");
@@ -444,8 +823,8 @@ pub fn env(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     let session: Session = config.try_into()?;
 
     #[allow(clippy::unwrap_used)]
-    let label = matches.value_of("label").unwrap();
-    let record = session.get_record(label)?;
+    let label: Label = matches.value_of("label").unwrap().parse()?;
+    let record = session.get_record(&label)?;
 
     let environment = match record.body {
         RecordBody::Environment(e) => e,
@@ -487,18 +866,17 @@ pub fn edit(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("editor: {}, args: {:?}", editor, editor_args);
 
     #[allow(clippy::unwrap_used)]
-    let label = matches.value_of("label").unwrap();
-    let record = session.get_record(label)?;
+    let label: Label = matches.value_of("label").unwrap().parse()?;
+    let record = session.get_record(&label)?;
 
     let mut file = tempfile::NamedTempFile::new()?;
     file.write_all(&serde_json::to_vec_pretty(&record)?)?;
 
-    if !process::Command::new(&editor)
+    let output = process::Command::new(&editor)
         .args(&editor_args)
         .arg(file.path())
-        .output()
-        .map_or(false, |o| o.status.success())
-    {
+        .output();
+    if !matches!(output, Ok(o) if o.status.success()) {
         return Err(anyhow!("failed to run the editor"));
     }
 
@@ -510,7 +888,7 @@ pub fn edit(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     let mut record = serde_json::from_slice::<record::Record>(&record_contents)?;
 
     // Users can't modify these fields, at least not with `kbs2 edit`.
-    record.label = label.into();
+    record.label = label.to_string();
     record.timestamp = util::current_timestamp();
 
     session.add_record(&record)?;
@@ -552,6 +930,15 @@ pub fn rewrap(matches: &ArgMatches, config: &config::Config) -> Result<()> {
         return Err(anyhow!("config specifies a bare key; nothing to rewrap"));
     }
 
+    if ssh::is_ssh_public_key(&config.public_key) {
+        return Err(anyhow!(
+            "can't rewrap an SSH key; use `ssh-keygen -p` to change its passphrase"
+        ));
+    }
+
+    // Keep other `kbs2` processes from using the keyfile while it's being replaced.
+    let _lock = StoreLock::acquire(&config.store, LockKind::Exclusive, lock::LOCK_TIMEOUT)?;
+
     if !matches.is_present("no-backup") {
         let keyfile_backup: PathBuf = format!("{}.old", &config.keyfile).into();
         if keyfile_backup.exists() && !matches.is_present("force") {
@@ -573,6 +960,46 @@ pub fn rewrap(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     backend::RageLib::rewrap_keyfile(&config.keyfile, old, new)
 }
 
+/// Implements the `kbs2 encrypt-labels` command.
+pub fn encrypt_labels(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("attempting to encrypt the store's labels");
+
+    if config.encrypted_labels {
+        return Err(anyhow!("config already specifies encrypted labels"));
+    }
+
+    let session: Session = config.try_into()?;
+
+    println!(
+        "This subcommand RENAMES every record in your store ({}) and REWRITES your config",
+        session.config.store
+    );
+
+    if !Confirm::new()
+        .default(false)
+        .with_prompt("Are you SURE you want to continue?")
+        .interact()?
+    {
+        return Ok(());
+    }
+
+    session.encrypt_labels()?;
+
+    // Dupe the current config, enable encrypted labels, and write it back.
+    let config = config::Config {
+        encrypted_labels: true,
+        ..config.clone()
+    };
+    util::atomic_write(
+        Path::new(&config.config_dir).join(config::CONFIG_BASENAME),
+        toml::to_string(&config)?,
+    )?;
+
+    println!("All done.");
+
+    Ok(())
+}
+
 /// Implements the `kbs2 rekey` command.
 pub fn rekey(matches: &ArgMatches, config: &config::Config) -> Result<()> {
     log::debug!("attempting to rekey the store");
@@ -583,8 +1010,17 @@ pub fn rekey(matches: &ArgMatches, config: &config::Config) -> Result<()> {
         return Err(anyhow!("rekeying is only supported on wrapped keys"));
     }
 
+    // Rekeying replaces the keyfile with a new age keypair, which would clobber an SSH key.
+    if ssh::is_ssh_public_key(&config.public_key) {
+        return Err(anyhow!("rekeying isn't supported on SSH keys"));
+    }
+
     let session: Session = config.try_into()?;
 
+    // Hold the store for the entire rekey, so that no other `kbs2` process can read or write
+    // records while they're in flux.
+    let _lock = session.lock(LockKind::Exclusive)?;
+
     println!(
         "This subcommand REKEYS your entire store ({}) and REWRITES your config",
         session.config.store
@@ -636,26 +1072,14 @@ pub fn rekey(matches: &ArgMatches, config: &config::Config) -> Result<()> {
             ));
         }
 
-        std::fs::create_dir_all(&store_backup)?;
-        for label in session.record_labels()? {
-            std::fs::copy(
-                Path::new(&config.store).join(&label),
-                store_backup.join(&label),
-            )?;
-        }
+        // NOTE(ww): This copies the store wholesale rather than record-by-record,
+        // so that prior revisions and any encrypted label index come along too.
+        util::copy_dir(&config.store, &store_backup)?;
         println!("Backup of the OLD store saved to: {:?}", &store_backup);
     }
 
-    // Decrypt and collect all records.
-    let records: Vec<Secret<record::Record>> = {
-        let records: Result<Vec<record::Record>> = session
-            .record_labels()?
-            .iter()
-            .map(|l| session.get_record(l))
-            .collect();
-
-        records?.into_iter().map(Secret::new).collect()
-    };
+    // Decrypt and collect all records, prior revisions, and the label index (if any).
+    let contents = session.contents()?;
 
     // Get a new master password.
     let new_password = util::get_password(Some("NEW master password: "), &config.pinentry)?;
@@ -669,30 +1093,199 @@ pub fn rekey(matches: &ArgMatches, config: &config::Config) -> Result<()> {
         public_key,
         ..config.clone()
     };
-    std::fs::write(
+    util::atomic_write(
         Path::new(&config.config_dir).join(config::CONFIG_BASENAME),
         toml::to_string(&config)?,
     )?;
 
     // Flush the stale key from the active agent, and add the new key to the agent.
-    // NOTE(ww): This scope is essential: we need to drop this client before we
-    // create the new session below. Why? Because the session contains its
-    // own agent client, and the current agent implementation only allows a
-    // single client at a time. Clients yield their access by closing their
-    // underlying socket, so we need to drop here to prevent a deadlock.
-    {
-        let client = agent::Client::new()?;
-        client.flush_keys()?;
-        client.add_key(&config.public_key, &config.keyfile, new_password)?;
-    }
+    let client = agent::Client::new()?;
+    client.flush_keys()?;
+    client.add_key(
+        &config.public_key,
+        &config.keyfile,
+        new_password,
+        config.agent_key_options(),
+    )?;
 
     // Create a new session from the new config and use it to re-encrypt each record.
     println!("Re-encrypting all records, be patient...");
     let session: Session = (&config).try_into()?;
-    for record in records {
-        log::debug!("re-encrypting {}", record.expose_secret().label);
-        session.add_record(record.expose_secret())?;
+    session.rewrite_contents(&contents)?;
+
+    println!("All done.");
+
+    Ok(())
+}
+
+/// Implements the `kbs2 recipients` command.
+pub fn recipients(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    log::debug!("recipients subcommand dispatch");
+
+    match matches.subcommand() {
+        Some(("add", matches)) => recipients_add(matches, config),
+        Some(("remove", matches)) => recipients_remove(matches, config),
+        Some(("list", matches)) => recipients_list(matches, config),
+        Some(("check", matches)) => recipients_check(matches, config),
+        _ => unreachable!(),
     }
+}
+
+/// Implements the `kbs2 recipients add` subcommand.
+fn recipients_add(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    #[allow(clippy::unwrap_used)]
+    let pubkeys: Vec<&str> = matches.values_of("pubkey").unwrap().collect();
+
+    let mut recipients = config.recipients.clone();
+    for pubkey in pubkeys {
+        // NOTE(ww): An external age binary may support kinds of recipients (e.g. plugins)
+        // that we can't parse, so we leave validating them up to it.
+        if config.backend == config::BackendKind::RageLib {
+            backend::parse_recipient(pubkey)?;
+        }
+
+        if pubkey == config.public_key || recipients.iter().any(|r| r == pubkey) {
+            return Err(anyhow!("{} is already a recipient", pubkey));
+        }
+
+        recipients.push(pubkey.into());
+    }
+
+    reencrypt_to_recipients(config, recipients)
+}
+
+/// Implements the `kbs2 recipients remove` subcommand.
+fn recipients_remove(matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    #[allow(clippy::unwrap_used)]
+    let pubkeys: Vec<&str> = matches.values_of("pubkey").unwrap().collect();
+
+    let mut recipients = config.recipients.clone();
+    for pubkey in pubkeys {
+        if pubkey == config.public_key {
+            return Err(anyhow!("can't remove this config's own public key"));
+        }
+
+        let len = recipients.len();
+        recipients.retain(|r| r != pubkey);
+        if recipients.len() == len {
+            return Err(anyhow!("{} is not a recipient", pubkey));
+        }
+    }
+
+    reencrypt_to_recipients(config, recipients)
+}
+
+/// Implements the `kbs2 recipients list` subcommand.
+fn recipients_list(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    println!("{}", config.public_key);
+    for recipient in &config.recipients {
+        println!("{}", recipient);
+    }
+
+    Ok(())
+}
+
+/// Implements the `kbs2 recipients check` subcommand.
+fn recipients_check(_matches: &ArgMatches, config: &config::Config) -> Result<()> {
+    let session: Session = config.try_into()?;
+    let _lock = session.lock(LockKind::Shared)?;
+
+    let mut mismatches = 0;
+    for label in session.record_labels()? {
+        if session.get_record(&label).is_err() {
+            println!("{}: can't be decrypted with this config's key", label);
+            mismatches += 1;
+            continue;
+        }
+
+        // NOTE(ww): SSH recipients are identified in each record's header by a hash of their
+        // public key, so they're checked individually. age doesn't record which X25519 keys a
+        // record is encrypted to, so those are only checked by count.
+        let (expected_ssh, expected_age): (Vec<_>, Vec<_>) = session
+            .recipient_policy(&label)?
+            .into_iter()
+            .partition(|r| ssh::is_ssh_public_key(r));
+        let (actual_ssh, actual_age): (Vec<_>, Vec<_>) = session
+            .record_recipient_stanzas(&label)?
+            .into_iter()
+            .partition(|s| s.ssh_tag().is_some());
+
+        let mut matches = true;
+        let mut expected_tags = vec![];
+        for recipient in expected_ssh {
+            let tag = ssh::recipient_tag(&recipient)?;
+            if !actual_ssh.iter().any(|s| s.ssh_tag() == Some(&tag)) {
+                println!("{}: not encrypted to the SSH key {}", label, recipient);
+                matches = false;
+            }
+            expected_tags.push(tag);
+        }
+
+        for stanza in actual_ssh {
+            if let Some(tag) = stanza.ssh_tag() {
+                if !expected_tags.iter().any(|t| t == tag) {
+                    println!(
+                        "{}: encrypted to an unexpected {} key (tag {})",
+                        label, stanza.kind, tag
+                    );
+                    matches = false;
+                }
+            }
+        }
+
+        if actual_age.len() != expected_age.len() {
+            println!(
+                "{}: encrypted to {} age recipient(s), but should be encrypted to {} (count only)",
+                label,
+                actual_age.len(),
+                expected_age.len()
+            );
+            matches = false;
+        }
+
+        if !matches {
+            mismatches += 1;
+        }
+    }
+
+    if mismatches > 0 {
+        return Err(anyhow!(
+            "{} record(s) aren't encrypted to their expected recipients",
+            mismatches
+        ));
+    }
+
+    Ok(())
+}
+
+/// Rewrites the config with the given list of additional recipients, and re-encrypts
+/// every record in the store to the new set of recipients.
+fn reencrypt_to_recipients(config: &config::Config, recipients: Vec<String>) -> Result<()> {
+    let session: Session = config.try_into()?;
+
+    // Hold the store until every record has been re-encrypted, so that no other `kbs2`
+    // process writes a record to the old set of recipients in the meantime.
+    let _lock = session.lock(LockKind::Exclusive)?;
+
+    let contents = session.contents()?;
+
+    // Dupe the current config and update only the recipients.
+    let config = config::Config {
+        recipients,
+        ..config.clone()
+    };
+
+    println!("Re-encrypting all records, be patient...");
+    let session: Session = (&config).try_into()?;
+    session.rewrite_contents(&contents)?;
+
+    // NOTE(ww): Only write the new recipients back once every record has been re-encrypted
+    // to them, so that a failure above never leaves the config naming recipients that
+    // the store isn't actually encrypted to.
+    util::atomic_write(
+        Path::new(&config.config_dir).join(config::CONFIG_BASENAME),
+        toml::to_string(&config)?,
+    )?;
 
     println!("All done.");
 
@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 use std::env;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
@@ -10,8 +11,11 @@ use lazy_static::lazy_static;
 use secrecy::SecretString;
 use serde::{de, Deserialize, Serialize};
 
-use crate::kbs2::backend::{Backend, RageLib};
+use crate::kbs2::agent::KeyOptions;
+use crate::kbs2::backend::RageLib;
 use crate::kbs2::generator::Generator;
+use crate::kbs2::record::{self, FieldKind};
+use crate::kbs2::session;
 use crate::kbs2::util;
 
 /// The default base config directory name, placed relative to the user's config
@@ -69,10 +73,60 @@ pub struct Config {
     #[serde(default = "default_as_true")]
     pub wrapped: bool,
 
+    /// Whether or not the agent hands the unwrapped private component to `kbs2`
+    /// processes, rather than decrypting on their behalf.
+    #[serde(rename = "agent-key-export")]
+    #[serde(default)]
+    pub agent_key_export: bool,
+
+    /// The number of seconds that the agent holds the unwrapped private component for,
+    /// if limited.
+    #[serde(rename = "agent-key-ttl")]
+    #[serde(default)]
+    pub agent_key_ttl: Option<u64>,
+
+    /// The number of seconds that the unwrapped private component can go unused before
+    /// the agent forgets it, if limited.
+    #[serde(rename = "agent-key-idle-timeout")]
+    #[serde(default)]
+    pub agent_key_idle_timeout: Option<u64>,
+
+    /// Whether or not the agent asks the user (with `pinentry`) before each use of the
+    /// unwrapped private component.
+    #[serde(rename = "agent-key-confirm")]
+    #[serde(default)]
+    pub agent_key_confirm: bool,
+
+    /// The number of consecutive failed attempts to unwrap a key after which the agent
+    /// refuses to unwrap it until the agent is restarted, or `0` for no limit.
+    #[serde(rename = "agent-max-unwrap-attempts")]
+    #[serde(default = "default_agent_max_unwrap_attempts")]
+    pub agent_max_unwrap_attempts: u32,
+
+    /// The public keys of any other recipients that records are encrypted to,
+    /// in addition to `public-key`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipients: Vec<String>,
+
+    /// The age implementation used to encrypt and decrypt records.
+    #[serde(default)]
+    pub backend: BackendKind,
+
+    /// The age-compatible binary run by the `age-cli` backend.
+    #[serde(rename = "age-binary")]
+    #[serde(default = "default_age_binary")]
+    pub age_binary: String,
+
     /// The path to the directory where encrypted records are stored.
     #[serde(deserialize_with = "deserialize_with_tilde")]
     pub store: String,
 
+    /// Whether or not record labels are kept in an encrypted index, rather than
+    /// being used as the names of record files.
+    #[serde(rename = "encrypted-labels")]
+    #[serde(default)]
+    pub encrypted_labels: bool,
+
     /// The pinentry binary to use for password prompts.
     #[serde(default)]
     pub pinentry: Pinentry,
@@ -104,12 +158,30 @@ pub struct Config {
     #[serde(default)]
     pub generators: Vec<GeneratorConfig>,
 
+    /// Any record kinds declared by the user, in addition to the built-in kinds.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub kinds: Vec<KindConfig>,
+
+    /// Settings for the history of prior record revisions.
+    #[serde(default)]
+    pub history: HistoryConfig,
+
     /// Per-command configuration.
     #[serde(default)]
     pub commands: CommandConfigs,
 }
 
 impl Config {
+    /// Returns the options that the agent should hold this config's unwrapped key with.
+    pub fn agent_key_options(&self) -> KeyOptions {
+        KeyOptions {
+            exportable: self.agent_key_export,
+            ttl: self.agent_key_ttl,
+            idle_timeout: self.agent_key_idle_timeout,
+            confirm: self.agent_key_confirm,
+        }
+    }
+
     /// Calls a command as a hook, meaning:
     /// * The command is run with the `kbs2` store as its working directory
     /// * The command is run with `KBS2_HOOK=1` in its environment
@@ -155,6 +227,69 @@ impl Config {
 
         None
     }
+
+    /// Given the `name` of a user-defined record kind, return that kind's
+    /// declaration if it exists.
+    pub fn get_kind(&self, name: &str) -> Option<&KindConfig> {
+        self.kinds.iter().find(|k| k.name == name)
+    }
+
+    /// Returns whether or not `name` is a record kind known to this config,
+    /// either built-in or user-defined.
+    pub fn has_kind(&self, name: &str) -> bool {
+        record::RECORD_KINDS.contains(&name) || self.get_kind(name).is_some()
+    }
+
+    /// Checks the loaded configuration for semantic errors that the deserializer can't catch.
+    fn validate(&self) -> Result<()> {
+        for (idx, kind) in self.kinds.iter().enumerate() {
+            if kind.name.is_empty() {
+                return Err(anyhow!("record kinds must have a name"));
+            }
+
+            if record::RECORD_KINDS.contains(&kind.name.as_str()) {
+                return Err(anyhow!(
+                    "record kind shadows a built-in kind: {}",
+                    kind.name
+                ));
+            }
+
+            if self.kinds[..idx].iter().any(|k| k.name == kind.name) {
+                return Err(anyhow!("duplicate record kind: {}", kind.name));
+            }
+
+            if kind.fields.is_empty() {
+                return Err(anyhow!("record kind has no fields: {}", kind.name));
+            }
+
+            for (idx, field) in kind.fields.iter().enumerate() {
+                if field.name.is_empty() {
+                    return Err(anyhow!("record kind has an unnamed field: {}", kind.name));
+                }
+
+                if kind.fields[..idx].iter().any(|f| f.name == field.name) {
+                    return Err(anyhow!(
+                        "record kind has a duplicate field: {} (in {})",
+                        field.name,
+                        kind.name
+                    ));
+                }
+            }
+        }
+
+        // NOTE(ww): The label index is only encrypted to `recipients`, and recipients files
+        // would reveal the names of the folders they're in, so the two don't mix.
+        if self.encrypted_labels {
+            if let Some(recipients_path) = session::find_recipients_file(Path::new(&self.store))? {
+                return Err(anyhow!(
+                    "encrypted-labels can't be combined with per-folder recipients: {:?}",
+                    recipients_path
+                ));
+            }
+        }
+
+        Ok(())
+    }
 }
 
 /// A newtype wrapper around a `String`, used to provide a sensible default for `Config.pinentry`.
@@ -173,12 +308,28 @@ impl AsRef<OsStr> for Pinentry {
     }
 }
 
+/// The different age backends known to `kbs2`.
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// The age crate, linked into `kbs2` itself.
+    #[default]
+    RageLib,
+    /// An external age-compatible binary, configured with `age-binary`.
+    AgeCli,
+}
+
 /// The different types of generators known to `kbs2`.
+///
+/// NOTE(ww): Generators are told apart by their fields, so each kind denies unknown fields:
+/// otherwise, a misspelled or missing field would quietly turn a command or internal generator
+/// into a wordlist generator, which needs nothing but a name.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(untagged)]
 pub enum GeneratorConfig {
     Command(GeneratorCommandConfig),
     Internal(GeneratorInternalConfig),
+    Wordlist(GeneratorWordlistConfig),
 }
 
 impl GeneratorConfig {
@@ -186,12 +337,14 @@ impl GeneratorConfig {
         match self {
             GeneratorConfig::Command(g) => g as &dyn Generator,
             GeneratorConfig::Internal(g) => g as &dyn Generator,
+            GeneratorConfig::Wordlist(g) => g as &dyn Generator,
         }
     }
 }
 
 /// The configuration settings for a "command" generator.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct GeneratorCommandConfig {
     /// The name of the generator.
     pub name: String,
@@ -202,6 +355,7 @@ pub struct GeneratorCommandConfig {
 
 /// The configuration settings for an "internal" generator.
 #[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
 pub struct GeneratorInternalConfig {
     /// The name of the generator.
     pub name: String,
@@ -211,6 +365,34 @@ pub struct GeneratorInternalConfig {
 
     /// The number of characters to sample from the alphabet.
     pub length: u32,
+
+    /// The minimum number of lowercase letters in each secret.
+    #[serde(default, rename = "min-lower")]
+    pub min_lower: u32,
+
+    /// The minimum number of uppercase letters in each secret.
+    #[serde(default, rename = "min-upper")]
+    pub min_upper: u32,
+
+    /// The minimum number of digits in each secret.
+    #[serde(default, rename = "min-digits")]
+    pub min_digits: u32,
+
+    /// The minimum number of symbols (i.e., non-alphanumeric characters) in each secret.
+    #[serde(default, rename = "min-symbols")]
+    pub min_symbols: u32,
+
+    /// Any characters to remove from the alphabet, e.g. ambiguous ones like `0O1lI`.
+    #[serde(default)]
+    pub exclude: String,
+
+    /// Whether or not to reject secrets that contain the same character twice in a row.
+    #[serde(default, rename = "no-repeats")]
+    pub no_repeats: bool,
+
+    /// The class that the first character of each secret must belong to, if any.
+    #[serde(default, rename = "first-class")]
+    pub first_class: Option<CharacterClass>,
 }
 
 impl Default for GeneratorInternalConfig {
@@ -221,6 +403,136 @@ impl Default for GeneratorInternalConfig {
             // symbols but not commonly blacklisted ones (e.g. %, $).
             alphabet: "abcdefghijklmnopqrstuvwxyz0123456789(){}[]-_+=".into(),
             length: 16,
+            min_lower: 0,
+            min_upper: 0,
+            min_digits: 0,
+            min_symbols: 0,
+            exclude: "".into(),
+            no_repeats: false,
+            first_class: None,
+        }
+    }
+}
+
+/// The classes of characters that an "internal" generator's policy can refer to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CharacterClass {
+    /// ASCII lowercase letters.
+    Lower,
+    /// ASCII uppercase letters.
+    Upper,
+    /// ASCII letters of either case.
+    Letter,
+    /// ASCII digits.
+    Digit,
+    /// Any non-alphanumeric character.
+    Symbol,
+}
+
+impl fmt::Display for CharacterClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            CharacterClass::Lower => "lower",
+            CharacterClass::Upper => "upper",
+            CharacterClass::Letter => "letter",
+            CharacterClass::Digit => "digit",
+            CharacterClass::Symbol => "symbol",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// The configuration settings for a "wordlist" generator.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeneratorWordlistConfig {
+    /// The name of the generator.
+    pub name: String,
+
+    /// The path to the wordlist to sample from, e.g. the EFF's large wordlist.
+    /// Both plain (one word per line) and diceware-style (`11111 word`) lists are accepted.
+    #[serde(default, deserialize_with = "deserialize_optional_with_tilde")]
+    pub wordlist: Option<String>,
+
+    /// The number of words to sample from the wordlist.
+    #[serde(default = "default_wordlist_words")]
+    pub words: u32,
+
+    /// The separator to place between each word.
+    #[serde(default = "default_wordlist_separator")]
+    pub separator: String,
+
+    /// Whether or not to capitalize the first letter of each word.
+    #[serde(default)]
+    pub capitalize: bool,
+
+    /// The number of random digits to inject into the passphrase.
+    #[serde(default)]
+    pub digits: u32,
+
+    /// The number of random symbols to inject into the passphrase.
+    #[serde(default)]
+    pub symbols: u32,
+}
+
+/// The configuration settings for a user-defined record kind.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KindConfig {
+    /// The name of the kind, e.g. `"database"`.
+    pub name: String,
+
+    /// The fields of the kind, in the order that they're prompted for.
+    pub fields: Vec<KindFieldConfig>,
+}
+
+impl KindConfig {
+    /// Returns the kind's fields as `FieldKind`s, suitable for user input.
+    pub fn field_kinds(&self) -> Vec<FieldKind<'_>> {
+        self.fields
+            .iter()
+            .map(|f| {
+                if f.sensitive {
+                    FieldKind::Sensitive(&f.name)
+                } else {
+                    FieldKind::Insensitive(&f.name)
+                }
+            })
+            .collect()
+    }
+}
+
+/// The configuration settings for a single field in a user-defined record kind.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KindFieldConfig {
+    /// The name of the field, e.g. `"password"`.
+    pub name: String,
+
+    /// Whether or not the field is sensitive, i.e. prompted for without echo and
+    /// filled in by generators.
+    #[serde(default)]
+    pub sensitive: bool,
+}
+
+/// The configuration settings for record history.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(default)]
+pub struct HistoryConfig {
+    /// The maximum number of prior revisions kept for each record, or `0` to disable history.
+    #[serde(rename = "max-revisions")]
+    pub max_revisions: usize,
+
+    /// The maximum age, in seconds, of a prior revision before it's discarded.
+    #[serde(rename = "max-age")]
+    pub max_age: Option<u64>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_revisions: 10,
+            max_age: None,
         }
     }
 }
@@ -352,6 +664,30 @@ fn default_as_true() -> bool {
     true
 }
 
+#[doc(hidden)]
+#[inline]
+fn default_agent_max_unwrap_attempts() -> u32 {
+    10
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_wordlist_words() -> u32 {
+    6
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_wordlist_separator() -> String {
+    "-".into()
+}
+
+#[doc(hidden)]
+#[inline]
+fn default_age_binary() -> String {
+    "age".into()
+}
+
 /// Given a path to a `kbs2` configuration directory, initializes a configuration
 /// file and keypair within it.
 ///
@@ -403,18 +739,29 @@ pub fn initialize<P: AsRef<Path>>(
                 .into(),
             agent_autostart: true,
             wrapped: wrapped,
+            agent_key_export: false,
+            agent_key_ttl: None,
+            agent_key_idle_timeout: None,
+            agent_key_confirm: false,
+            agent_max_unwrap_attempts: 10,
+            recipients: vec![],
+            backend: Default::default(),
+            age_binary: "age".into(),
             store: store,
+            encrypted_labels: false,
             pinentry: Default::default(),
             pre_hook: None,
             post_hook: None,
             error_hook: None,
             reentrant_hooks: false,
             generators: vec![GeneratorConfig::Internal(Default::default())],
+            kinds: vec![],
+            history: Default::default(),
             commands: Default::default(),
         })?
     };
 
-    fs::write(config_dir.as_ref().join(CONFIG_BASENAME), serialized)?;
+    util::atomic_write(config_dir.as_ref().join(CONFIG_BASENAME), serialized)?;
 
     Ok(())
 }
@@ -426,13 +773,19 @@ pub fn load<P: AsRef<Path>>(config_dir: P) -> Result<Config> {
     let config_path = config_dir.join(CONFIG_BASENAME);
     let contents = fs::read_to_string(config_path)?;
 
-    Ok(Config {
+    let config = Config {
         config_dir: config_dir
             .to_str()
             .ok_or_else(|| anyhow!("unrepresentable config dir path: {:?}", config_dir))?
             .into(),
         ..toml::from_str(&contents).map_err(|e| anyhow!("config loading error: {}", e))?
-    })
+    };
+
+    config
+        .validate()
+        .map_err(|e| anyhow!("config loading error: {}", e))?;
+
+    Ok(config)
 }
 
 #[cfg(test)]
@@ -448,13 +801,36 @@ mod tests {
             keyfile: "not a real private key file".into(),
             agent_autostart: false,
             wrapped: false,
+            agent_key_export: false,
+            agent_key_ttl: None,
+            agent_key_idle_timeout: None,
+            agent_key_confirm: false,
+            agent_max_unwrap_attempts: 10,
+            recipients: vec![],
+            backend: Default::default(),
+            age_binary: "age".into(),
             store: "/tmp".into(),
+            encrypted_labels: false,
             pinentry: Default::default(),
             pre_hook: Some("true".into()),
             post_hook: Some("false".into()),
             error_hook: Some("true".into()),
             reentrant_hooks: false,
             generators: vec![GeneratorConfig::Internal(Default::default())],
+            kinds: vec![KindConfig {
+                name: "database".into(),
+                fields: vec![
+                    KindFieldConfig {
+                        name: "host".into(),
+                        sensitive: false,
+                    },
+                    KindFieldConfig {
+                        name: "password".into(),
+                        sensitive: true,
+                    },
+                ],
+            }],
+            history: Default::default(),
             commands: CommandConfigs {
                 rm: RmConfig {
                     post_hook: Some("this-command-does-not-exist".into()),
@@ -551,6 +927,36 @@ mod tests {
             assert_eq!(config_dir.path().to_str().unwrap(), config.config_dir);
             assert_eq!(store_dir.path().to_str().unwrap(), config.store);
         }
+
+        {
+            let config_dir = tempdir().unwrap();
+            let store_dir = tempdir().unwrap();
+            initialize(&config_dir, &store_dir, None).unwrap();
+
+            let config_path = config_dir.path().join(CONFIG_BASENAME);
+            let contents = fs::read_to_string(&config_path).unwrap();
+            fs::write(
+                &config_path,
+                contents.replace("encrypted-labels = false", "encrypted-labels = true"),
+            )
+            .unwrap();
+            assert!(load(&config_dir).unwrap().encrypted_labels);
+
+            fs::create_dir(store_dir.path().join("ops")).unwrap();
+            fs::write(
+                store_dir
+                    .path()
+                    .join("ops")
+                    .join(session::RECIPIENTS_BASENAME),
+                "",
+            )
+            .unwrap();
+
+            let err = load(&config_dir).unwrap_err();
+            assert!(err.to_string().starts_with(
+                "config loading error: encrypted-labels can't be combined with per-folder recipients"
+            ));
+        }
     }
 
     #[test]
@@ -596,4 +1002,137 @@ mod tests {
         assert!(config.get_generator("default").is_some());
         assert!(config.get_generator("nonexistent-generator").is_none());
     }
+
+    #[test]
+    fn test_load_generators() {
+        let config_dir = tempdir().unwrap();
+        let store_dir = tempdir().unwrap();
+        initialize(&config_dir, &store_dir, None).unwrap();
+
+        let config_path = config_dir.path().join(CONFIG_BASENAME);
+        let contents = fs::read_to_string(&config_path).unwrap();
+
+        let generators = r#"
+[[generators]]
+name = "phrase"
+wordlist = "~/eff_large_wordlist.txt"
+capitalize = true
+digits = 1
+"#;
+        fs::write(&config_path, format!("{}{}", contents, generators)).unwrap();
+
+        let config = load(&config_dir).unwrap();
+        assert!(config.get_generator("default").is_some());
+        assert!(config.get_generator("phrase").is_some());
+
+        match config.generators.last().unwrap() {
+            GeneratorConfig::Wordlist(g) => {
+                let wordlist = g.wordlist.as_deref().unwrap();
+                assert!(!wordlist.starts_with('~'));
+                assert!(wordlist.ends_with("/eff_large_wordlist.txt"));
+                assert_eq!(g.words, 6);
+                assert_eq!(g.separator, "-");
+                assert!(g.capitalize);
+                assert_eq!(g.digits, 1);
+                assert_eq!(g.symbols, 0);
+            }
+            _ => panic!("expected a wordlist generator"),
+        }
+
+        // A wordlist generator doesn't need a wordlist to be configured.
+        fs::write(
+            &config_path,
+            format!("{}[[generators]]\nname = \"phrase\"\n", contents),
+        )
+        .unwrap();
+        let config = load(&config_dir).unwrap();
+        match config.generators.last().unwrap() {
+            GeneratorConfig::Wordlist(g) => assert!(g.wordlist.is_none()),
+            _ => panic!("expected a wordlist generator"),
+        }
+
+        // Misspelled or missing fields are errors, rather than making a wordlist generator.
+        for generator in &[
+            "name = \"pwgen\"\ncomand = \"pwgen 16 1\"\n",
+            "name = \"hexonly\"\nalphabet = \"0123456789abcdef\"\n",
+            "name = \"hexonly\"\nalphabet = \"0123456789abcdef\"\nlenght = 16\n",
+            "name = \"phrase\"\nwordlst = \"~/eff_large_wordlist.txt\"\n",
+        ] {
+            fs::write(
+                &config_path,
+                format!("{}[[generators]]\n{}", contents, generator),
+            )
+            .unwrap();
+
+            let err = load(&config_dir).unwrap_err();
+            assert!(err.to_string().starts_with("config loading error: "));
+        }
+    }
+
+    #[test]
+    fn test_get_kind() {
+        let config = dummy_config_unwrapped_key();
+
+        assert!(config.get_kind("database").is_some());
+        assert!(config.get_kind("login").is_none());
+        assert!(config.get_kind("nonexistent-kind").is_none());
+
+        assert!(config.has_kind("database"));
+        assert!(config.has_kind("login"));
+        assert!(!config.has_kind("nonexistent-kind"));
+
+        let fields = config.get_kind("database").unwrap().field_kinds();
+        assert!(matches!(fields[0], FieldKind::Insensitive("host")));
+        assert!(matches!(fields[1], FieldKind::Sensitive("password")));
+    }
+
+    #[test]
+    fn test_load_kinds() {
+        let config_dir = tempdir().unwrap();
+        let store_dir = tempdir().unwrap();
+        initialize(&config_dir, &store_dir, None).unwrap();
+
+        let config_path = config_dir.path().join(CONFIG_BASENAME);
+        let contents = fs::read_to_string(&config_path).unwrap();
+
+        {
+            let kinds = r#"
+[[kinds]]
+name = "database"
+fields = [{ name = "host" }, { name = "password", sensitive = true }]
+"#;
+            fs::write(&config_path, format!("{}{}", contents, kinds)).unwrap();
+
+            let config = load(&config_dir).unwrap();
+            let kind = config.get_kind("database").unwrap();
+            assert_eq!(kind.fields.len(), 2);
+            assert!(!kind.fields[0].sensitive);
+            assert!(kind.fields[1].sensitive);
+        }
+
+        for (kinds, message) in &[
+            (
+                "[[kinds]]\nname = \"login\"\nfields = [{ name = \"a\" }]\n",
+                "config loading error: record kind shadows a built-in kind: login",
+            ),
+            (
+                "[[kinds]]\nname = \"x\"\nfields = [{ name = \"a\" }]\n\
+                 [[kinds]]\nname = \"x\"\nfields = [{ name = \"b\" }]\n",
+                "config loading error: duplicate record kind: x",
+            ),
+            (
+                "[[kinds]]\nname = \"x\"\nfields = []\n",
+                "config loading error: record kind has no fields: x",
+            ),
+            (
+                "[[kinds]]\nname = \"x\"\nfields = [{ name = \"a\" }, { name = \"a\" }]\n",
+                "config loading error: record kind has a duplicate field: a (in x)",
+            ),
+        ] {
+            fs::write(&config_path, format!("{}{}", contents, kinds)).unwrap();
+
+            let err = load(&config_dir).unwrap_err();
+            assert_eq!(err.to_string(), *message);
+        }
+    }
 }
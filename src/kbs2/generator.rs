@@ -1,4 +1,7 @@
-use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
 use rand::Rng;
 
 use crate::kbs2::config;
@@ -26,6 +29,95 @@ impl Generator for config::GeneratorCommandConfig {
     }
 }
 
+/// The number of candidates that an internal generator samples before giving up on
+/// satisfying its policy.
+const MAX_POLICY_ATTEMPTS: u32 = 10_000;
+
+impl config::CharacterClass {
+    /// Returns whether the given character belongs to this class.
+    fn contains(self, c: char) -> bool {
+        match self {
+            config::CharacterClass::Lower => c.is_ascii_lowercase(),
+            config::CharacterClass::Upper => c.is_ascii_uppercase(),
+            config::CharacterClass::Letter => c.is_ascii_alphabetic(),
+            config::CharacterClass::Digit => c.is_ascii_digit(),
+            config::CharacterClass::Symbol => !c.is_ascii_alphanumeric(),
+        }
+    }
+}
+
+impl config::GeneratorInternalConfig {
+    /// Returns the minimum count for each character class constrained by the policy.
+    fn minimums(&self) -> [(config::CharacterClass, u32); 4] {
+        [
+            (config::CharacterClass::Lower, self.min_lower),
+            (config::CharacterClass::Upper, self.min_upper),
+            (config::CharacterClass::Digit, self.min_digits),
+            (config::CharacterClass::Symbol, self.min_symbols),
+        ]
+    }
+
+    /// Checks the policy against the (post-exclusion) alphabet, rejecting any policy
+    /// that no secret could ever satisfy.
+    fn check_policy(&self, alphabet: &[char]) -> Result<()> {
+        if alphabet.is_empty() {
+            return Err(anyhow!("generator alphabet is empty after exclusions"));
+        }
+
+        let mut required = 0;
+        for (class, min) in self.minimums().iter() {
+            if *min > 0 && !alphabet.iter().any(|c| class.contains(*c)) {
+                return Err(anyhow!(
+                    "generator requires {} characters, but its alphabet has none",
+                    class
+                ));
+            }
+            required += min;
+        }
+
+        if required > self.length {
+            return Err(anyhow!(
+                "generator requires {} characters, but its length is only {}",
+                required,
+                self.length
+            ));
+        }
+
+        if let Some(class) = self.first_class {
+            if self.length == 0 || !alphabet.iter().any(|c| class.contains(*c)) {
+                return Err(anyhow!(
+                    "generator requires a leading {} character, but can't produce one",
+                    class
+                ));
+            }
+        }
+
+        if self.no_repeats && self.length > 1 && alphabet.len() < 2 {
+            return Err(anyhow!(
+                "generator forbids repeats, but its alphabet has only one character"
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether the given candidate secret satisfies the policy.
+    fn satisfies_policy(&self, candidate: &[char]) -> bool {
+        let minimums_met = self.minimums().iter().all(|(class, min)| {
+            candidate.iter().filter(|c| class.contains(**c)).count() >= *min as usize
+        });
+
+        let first_ok = match (self.first_class, candidate.first()) {
+            (Some(class), Some(c)) => class.contains(*c),
+            _ => true,
+        };
+
+        let repeats_ok = !self.no_repeats || candidate.windows(2).all(|w| w[0] != w[1]);
+
+        minimums_met && first_ok && repeats_ok
+    }
+}
+
 impl Generator for config::GeneratorInternalConfig {
     fn name(&self) -> &str {
         &self.name
@@ -37,18 +129,118 @@ impl Generator for config::GeneratorInternalConfig {
             return Err(anyhow!("generator alphabet contains non-ascii characters"));
         }
 
+        let alphabet = self
+            .alphabet
+            .chars()
+            .filter(|c| !self.exclude.contains(*c))
+            .collect::<Vec<_>>();
+        self.check_policy(&alphabet)?;
+
+        // NOTE(ww): We enforce the policy by sampling whole candidates uniformly and
+        // rejecting those that don't satisfy it, rather than by placing the required
+        // characters directly. This keeps every acceptable secret equally likely.
+        let mut rng = rand::thread_rng();
+        for _ in 0..MAX_POLICY_ATTEMPTS {
+            let candidate = (0..self.length)
+                .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+                .collect::<Vec<_>>();
+
+            if self.satisfies_policy(&candidate) {
+                return Ok(candidate.into_iter().collect());
+            }
+        }
+
+        Err(anyhow!(
+            "couldn't generate a secret satisfying the generator's policy after {} attempts",
+            MAX_POLICY_ATTEMPTS
+        ))
+    }
+}
+
+/// The digits that a wordlist generator may inject into a passphrase.
+const WORDLIST_DIGITS: &[u8] = b"0123456789";
+
+/// The symbols that a wordlist generator may inject into a passphrase.
+const WORDLIST_SYMBOLS: &[u8] = b"!#*+=?@^";
+
+impl config::GeneratorWordlistConfig {
+    /// Loads the words from the configured wordlist, ignoring any diceware-style
+    /// roll numbers that precede them.
+    ///
+    /// NOTE(ww): Each word is kept only once, so that a word repeated in the wordlist
+    /// isn't picked more often than the others.
+    fn load_words(&self) -> Result<Vec<String>> {
+        let wordlist = self.wordlist.as_deref().ok_or_else(|| {
+            anyhow!(
+                "no wordlist configured for generator {}; set its wordlist to a file, \
+                 e.g. the EFF's large wordlist",
+                self.name
+            )
+        })?;
+        let contents = fs::read_to_string(wordlist)
+            .with_context(|| format!("failed to read wordlist: {}", wordlist))?;
+
+        let mut seen = HashSet::new();
+        let words = contents
+            .lines()
+            .filter_map(|line| line.split_whitespace().last())
+            .filter(|word| seen.insert(*word))
+            .map(String::from)
+            .collect::<Vec<_>>();
+
+        if words.is_empty() {
+            return Err(anyhow!("wordlist is empty: {}", wordlist));
+        }
+
+        Ok(words)
+    }
+}
+
+impl Generator for config::GeneratorWordlistConfig {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn secret(&self) -> Result<String> {
+        if self.words == 0 {
+            return Err(anyhow!("generator must produce at least one word"));
+        }
+
+        let wordlist = self.load_words()?;
+
         let mut rng = rand::thread_rng();
-        let alphabet = self.alphabet.as_bytes();
-        let secret = (0..self.length)
-            .map(|_| alphabet[rng.gen_range(0..alphabet.len())] as char)
-            .collect::<String>();
+        let mut words = (0..self.words)
+            .map(|_| {
+                let word = &wordlist[rng.gen_range(0..wordlist.len())];
+                let mut chars = word.chars();
 
-        Ok(secret)
+                match chars.next() {
+                    Some(first) if self.capitalize => first.to_uppercase().chain(chars).collect(),
+                    _ => word.clone(),
+                }
+            })
+            .collect::<Vec<String>>();
+
+        // NOTE(ww): Each injected character goes at the end of a randomly chosen word,
+        // which keeps the passphrase memorable while still adding a little entropy.
+        let injections = (0..self.digits)
+            .map(|_| WORDLIST_DIGITS)
+            .chain((0..self.symbols).map(|_| WORDLIST_SYMBOLS));
+        for alphabet in injections {
+            let index = rng.gen_range(0..words.len());
+            words[index].push(alphabet[rng.gen_range(0..alphabet.len())] as char);
+        }
+
+        Ok(words.join(&self.separator))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Write;
+
+    use tempfile::NamedTempFile;
+
     use super::*;
 
     fn dummy_command_generator(command: &str) -> Box<dyn Generator> {
@@ -63,9 +255,28 @@ mod tests {
             name: "dummy-internal".into(),
             alphabet: alphabet.into(),
             length: 5,
+            ..Default::default()
         })
     }
 
+    fn dummy_wordlist_generator(wordlist: &NamedTempFile) -> config::GeneratorWordlistConfig {
+        config::GeneratorWordlistConfig {
+            name: "dummy-wordlist".into(),
+            wordlist: Some(wordlist.path().to_str().unwrap().into()),
+            words: 4,
+            separator: " ".into(),
+            capitalize: false,
+            digits: 0,
+            symbols: 0,
+        }
+    }
+
+    fn dummy_wordlist(contents: &str) -> NamedTempFile {
+        let mut wordlist = NamedTempFile::new().unwrap();
+        wordlist.write_all(contents.as_bytes()).unwrap();
+        wordlist
+    }
+
     #[test]
     fn test_name() {
         {
@@ -77,6 +288,12 @@ mod tests {
             let gen = dummy_internal_generator("abc");
             assert_eq!(gen.name(), "dummy-internal");
         }
+
+        {
+            let wordlist = dummy_wordlist("abacus\n");
+            let gen = dummy_wordlist_generator(&wordlist);
+            assert_eq!(gen.name(), "dummy-wordlist");
+        }
     }
 
     #[test]
@@ -109,4 +326,206 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_wordlist_secret() {
+        {
+            let wordlist = dummy_wordlist("11111\tabacus\n11112\tabdomen\n\n11113\tabdominal\n");
+            let gen = dummy_wordlist_generator(&wordlist);
+            let secret = gen.secret().unwrap();
+            let words = secret.split(' ').collect::<Vec<_>>();
+
+            assert_eq!(words.len(), 4);
+            assert!(words
+                .iter()
+                .all(|w| ["abacus", "abdomen", "abdominal"].contains(w)));
+        }
+
+        {
+            let wordlist = dummy_wordlist("abacus\n");
+            let gen = config::GeneratorWordlistConfig {
+                separator: ".".into(),
+                capitalize: true,
+                ..dummy_wordlist_generator(&wordlist)
+            };
+            assert_eq!(gen.secret().unwrap(), "Abacus.Abacus.Abacus.Abacus");
+        }
+
+        {
+            let wordlist = dummy_wordlist("abacus\n");
+            let gen = config::GeneratorWordlistConfig {
+                separator: "".into(),
+                digits: 3,
+                symbols: 2,
+                ..dummy_wordlist_generator(&wordlist)
+            };
+            let secret = gen.secret().unwrap();
+
+            assert_eq!(secret.len(), 4 * "abacus".len() + 5);
+            assert_eq!(secret.chars().filter(|c| c.is_ascii_digit()).count(), 3);
+            assert_eq!(
+                secret
+                    .bytes()
+                    .filter(|c| WORDLIST_SYMBOLS.contains(c))
+                    .count(),
+                2
+            );
+        }
+
+        {
+            let wordlist = dummy_wordlist("\n\n");
+            let gen = dummy_wordlist_generator(&wordlist);
+            let err = gen.secret().unwrap_err();
+            assert!(err.to_string().starts_with("wordlist is empty: "));
+        }
+
+        {
+            let wordlist = dummy_wordlist("11111 abacus\nabacus\n11112 abdomen\nabacus\n");
+            let gen = dummy_wordlist_generator(&wordlist);
+            assert_eq!(gen.load_words().unwrap(), vec!["abacus", "abdomen"]);
+        }
+
+        {
+            let wordlist = dummy_wordlist("abacus\n");
+            let gen = config::GeneratorWordlistConfig {
+                wordlist: None,
+                ..dummy_wordlist_generator(&wordlist)
+            };
+            let err = gen.secret().unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("no wordlist configured for generator dummy-wordlist"));
+        }
+
+        {
+            let wordlist = dummy_wordlist("abacus\n");
+            let gen = config::GeneratorWordlistConfig {
+                words: 0,
+                ..dummy_wordlist_generator(&wordlist)
+            };
+            let err = gen.secret().unwrap_err();
+            assert_eq!(err.to_string(), "generator must produce at least one word");
+        }
+    }
+
+    #[test]
+    fn test_internal_policy() {
+        let policy = config::GeneratorInternalConfig {
+            name: "policy".into(),
+            alphabet: "abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_+=".into(),
+            length: 12,
+            min_lower: 2,
+            min_upper: 2,
+            min_digits: 3,
+            min_symbols: 1,
+            exclude: "0O1lI".into(),
+            no_repeats: true,
+            first_class: Some(config::CharacterClass::Letter),
+        };
+
+        for _ in 0..1000 {
+            let secret = policy.secret().unwrap();
+            let chars = secret.chars().collect::<Vec<_>>();
+
+            assert_eq!(chars.len(), 12);
+            assert!(chars.iter().filter(|c| c.is_ascii_lowercase()).count() >= 2);
+            assert!(chars.iter().filter(|c| c.is_ascii_uppercase()).count() >= 2);
+            assert!(chars.iter().filter(|c| c.is_ascii_digit()).count() >= 3);
+            assert!(chars.iter().filter(|c| !c.is_ascii_alphanumeric()).count() >= 1);
+            assert!(!chars.iter().any(|c| "0O1lI".contains(*c)));
+            assert!(chars.windows(2).all(|w| w[0] != w[1]));
+            assert!(chars[0].is_ascii_alphabetic());
+        }
+    }
+
+    #[test]
+    fn test_internal_policy_unbiased() {
+        // With a single required digit, every position should still be equally likely
+        // to hold it: rejection sampling mustn't favor any particular placement.
+        let policy = config::GeneratorInternalConfig {
+            name: "policy".into(),
+            alphabet: "ab0".into(),
+            length: 4,
+            min_digits: 1,
+            ..Default::default()
+        };
+
+        let samples = 20000;
+        let mut counts = [0u32; 4];
+        for _ in 0..samples {
+            let secret = policy.secret().unwrap();
+            for (i, c) in secret.chars().enumerate() {
+                if c == '0' {
+                    counts[i] += 1;
+                }
+            }
+        }
+
+        let expected = counts.iter().sum::<u32>() as f64 / 4.0;
+        for count in counts.iter() {
+            assert!((*count as f64 - expected).abs() < expected * 0.1);
+        }
+    }
+
+    #[test]
+    fn test_internal_policy_unsatisfiable() {
+        let base = config::GeneratorInternalConfig {
+            name: "policy".into(),
+            alphabet: "abc123".into(),
+            length: 4,
+            ..Default::default()
+        };
+
+        for (policy, message) in &[
+            (
+                config::GeneratorInternalConfig {
+                    exclude: "abc123".into(),
+                    ..base.clone()
+                },
+                "generator alphabet is empty after exclusions",
+            ),
+            (
+                config::GeneratorInternalConfig {
+                    min_upper: 1,
+                    ..base.clone()
+                },
+                "generator requires upper characters, but its alphabet has none",
+            ),
+            (
+                config::GeneratorInternalConfig {
+                    min_lower: 3,
+                    min_digits: 2,
+                    ..base.clone()
+                },
+                "generator requires 5 characters, but its length is only 4",
+            ),
+            (
+                config::GeneratorInternalConfig {
+                    first_class: Some(config::CharacterClass::Symbol),
+                    ..base.clone()
+                },
+                "generator requires a leading symbol character, but can't produce one",
+            ),
+            (
+                config::GeneratorInternalConfig {
+                    exclude: "bc123".into(),
+                    no_repeats: true,
+                    ..base.clone()
+                },
+                "generator forbids repeats, but its alphabet has only one character",
+            ),
+            (
+                config::GeneratorInternalConfig {
+                    length: 1,
+                    min_lower: 1,
+                    first_class: Some(config::CharacterClass::Digit),
+                    ..base.clone()
+                },
+                "couldn't generate a secret satisfying the generator's policy after 10000 attempts",
+            ),
+        ] {
+            let err = policy.secret().unwrap_err();
+            assert_eq!(err.to_string(), *message);
+        }
+    }
 }
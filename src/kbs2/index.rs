@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::kbs2::backend::Backend;
+use crate::kbs2::label::Label;
+use crate::kbs2::util;
+
+/// The basename of the encrypted label index, within the store.
+pub static INDEX_BASENAME: &str = ".index";
+
+/// The number of random bytes in each record's opaque identifier.
+const ID_LEN: usize = 16;
+
+/// Maps record labels to the opaque identifiers that their files are stored under,
+/// when the store is configured with `encrypted-labels`.
+///
+/// The index itself is stored encrypted, so the store reveals nothing about
+/// its records beyond their count.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Index {
+    ids: BTreeMap<String, String>,
+}
+
+impl Index {
+    /// Loads the index from the given store, returning an empty index if the store
+    /// doesn't have one yet.
+    pub fn load<P: AsRef<Path>>(store: P, backend: &dyn Backend) -> Result<Index> {
+        let index_path = store.as_ref().join(INDEX_BASENAME);
+
+        let encrypted = match fs::read_to_string(&index_path) {
+            Ok(encrypted) => encrypted,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        serde_json::from_slice(&backend.decrypt_bytes(&encrypted)?)
+            .map_err(|e| anyhow!("malformed label index: {}", e))
+    }
+
+    /// Encrypts and saves the index to the given store.
+    pub fn save<P: AsRef<Path>>(&self, store: P, backend: &dyn Backend) -> Result<()> {
+        let encrypted = backend.encrypt_bytes(serde_json::to_string(self)?.as_bytes())?;
+
+        util::atomic_write(store.as_ref().join(INDEX_BASENAME), encrypted)
+    }
+
+    /// Returns the opaque identifier for the given label, if it has one.
+    pub fn id(&self, label: &Label) -> Option<&str> {
+        self.ids.get(label.as_str()).map(String::as_str)
+    }
+
+    /// Returns the opaque identifier for the given label, assigning a new one if necessary.
+    pub fn id_or_insert(&mut self, label: &Label) -> &str {
+        if !self.ids.contains_key(label.as_str()) {
+            let id = loop {
+                let id = rand::thread_rng()
+                    .gen::<[u8; ID_LEN]>()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>();
+
+                if !self.ids.values().any(|i| *i == id) {
+                    break id;
+                }
+            };
+
+            self.ids.insert(label.to_string(), id);
+        }
+
+        &self.ids[label.as_str()]
+    }
+
+    /// Removes the given label from the index, returning its identifier if it had one.
+    pub fn remove(&mut self, label: &Label) -> Option<String> {
+        self.ids.remove(label.as_str())
+    }
+
+    /// Returns each label in the index, along with its opaque identifier.
+    pub fn entries(&self) -> Result<Vec<(Label, &str)>> {
+        self.ids
+            .iter()
+            .map(|(label, id)| Ok((label.parse()?, id.as_str())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::kbs2::backend::{Identities, RageLib};
+
+    fn dummy_backend() -> RageLib {
+        let key = age::x25519::Identity::generate();
+
+        RageLib {
+            pubkey: key.to_public().into(),
+            recipients: vec![],
+            identities: Identities::Local(vec![key.into()]),
+        }
+    }
+
+    fn label(label: &str) -> Label {
+        Label::new(label).unwrap()
+    }
+
+    #[test]
+    fn test_id_or_insert() {
+        let mut index = Index::default();
+
+        assert_eq!(index.id(&label("foo")), None);
+
+        let id = index.id_or_insert(&label("foo")).to_string();
+        assert_eq!(id.len(), ID_LEN * 2);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+
+        // Asking again returns the same identifier.
+        assert_eq!(index.id_or_insert(&label("foo")), id);
+        assert_eq!(index.id(&label("foo")), Some(id.as_str()));
+
+        // Different labels get different identifiers.
+        assert_ne!(index.id_or_insert(&label("work/foo")), id);
+        assert_eq!(index.entries().unwrap().len(), 2);
+
+        assert_eq!(index.remove(&label("foo")), Some(id));
+        assert_eq!(index.id(&label("foo")), None);
+        assert_eq!(index.entries().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_load_save() {
+        let store = tempdir().unwrap();
+        let backend = dummy_backend();
+
+        // A store without an index has an empty one.
+        assert_eq!(
+            Index::load(store.path(), &backend).unwrap(),
+            Index::default()
+        );
+
+        let mut index = Index::default();
+        index.id_or_insert(&label("foo"));
+        index.id_or_insert(&label("work/aws/prod"));
+        index.save(store.path(), &backend).unwrap();
+
+        // The saved index doesn't contain any labels in the clear.
+        let encrypted = fs::read_to_string(store.path().join(INDEX_BASENAME)).unwrap();
+        assert!(!encrypted.contains("foo"));
+        assert!(!encrypted.contains("work/aws/prod"));
+
+        assert_eq!(Index::load(store.path(), &backend).unwrap(), index);
+
+        // The index can't be loaded with a different key.
+        assert!(Index::load(store.path(), &dummy_backend()).is_err());
+    }
+}
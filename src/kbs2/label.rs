@@ -0,0 +1,153 @@
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+
+/// A validated record label.
+///
+/// Labels are slash-separated paths relative to the store, e.g. `work/aws/prod`.
+/// A valid label can't escape the store or collide with `kbs2`'s own files: it can't be
+/// absolute, contain NUL bytes, or have empty, `.`, `..`, or hidden (dot-prefixed) components.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Label(String);
+
+impl Label {
+    /// Validates the given string as a label.
+    pub fn new(label: &str) -> Result<Label> {
+        let invalid = |reason| Err(anyhow!("invalid label {:?}: {}", label, reason));
+
+        if label.is_empty() {
+            return invalid("labels can't be empty");
+        }
+
+        if label.contains('\0') {
+            return invalid("labels can't contain NUL bytes");
+        }
+
+        if label.starts_with('/') {
+            return invalid("labels can't be absolute paths");
+        }
+
+        for component in label.split('/') {
+            match component {
+                "" => return invalid("labels can't have empty components"),
+                "." | ".." => return invalid("labels can't have `.` or `..` components"),
+                c if c.starts_with('.') => return invalid("labels can't have hidden components"),
+                _ => {}
+            }
+        }
+
+        Ok(Label(label.into()))
+    }
+
+    /// Returns the label as a string slice.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl FromStr for Label {
+    type Err = anyhow::Error;
+
+    fn from_str(label: &str) -> Result<Self> {
+        Self::new(label)
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl PartialEq<str> for Label {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Label {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl AsRef<str> for Label {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for Label {
+    fn as_ref(&self) -> &Path {
+        Path::new(&self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        for label in &["foo", "foo.bar", "work/aws/prod", "pets.com", "a/b.c/d"] {
+            assert_eq!(Label::new(label).unwrap().as_str(), *label);
+        }
+    }
+
+    #[test]
+    fn test_new_rejects() {
+        for (label, message) in &[
+            ("", "invalid label \"\": labels can't be empty"),
+            (
+                "foo\0bar",
+                "invalid label \"foo\\0bar\": labels can't contain NUL bytes",
+            ),
+            (
+                "/etc/passwd",
+                "invalid label \"/etc/passwd\": labels can't be absolute paths",
+            ),
+            (
+                "../../.ssh/authorized_keys",
+                "invalid label \"../../.ssh/authorized_keys\": \
+                 labels can't have `.` or `..` components",
+            ),
+            (
+                "work/../../foo",
+                "invalid label \"work/../../foo\": labels can't have `.` or `..` components",
+            ),
+            (
+                "./foo",
+                "invalid label \"./foo\": labels can't have `.` or `..` components",
+            ),
+            (
+                ".history/foo",
+                "invalid label \".history/foo\": labels can't have hidden components",
+            ),
+            (
+                "work/.foo",
+                "invalid label \"work/.foo\": labels can't have hidden components",
+            ),
+            (
+                "work//foo",
+                "invalid label \"work//foo\": labels can't have empty components",
+            ),
+            (
+                "work/",
+                "invalid label \"work/\": labels can't have empty components",
+            ),
+        ] {
+            let err = Label::new(label).unwrap_err();
+            assert_eq!(err.to_string(), *message);
+        }
+    }
+
+    #[test]
+    fn test_from_str() {
+        let label: Label = "work/aws/prod".parse().unwrap();
+        assert_eq!(label.to_string(), "work/aws/prod");
+
+        assert!("../foo".parse::<Label>().is_err());
+    }
+}
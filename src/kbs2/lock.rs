@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use lazy_static::lazy_static;
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+
+/// The basename of the lock file, within the store.
+pub static LOCK_BASENAME: &str = ".lock";
+
+/// How long to wait for a contended store lock before giving up.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to sleep between attempts to take a contended store lock.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+lazy_static! {
+    /// The store locks currently held by this process, keyed by lock file path.
+    ///
+    /// NOTE(ww): `flock(2)` locks belong to open file descriptions, so a second `open` +
+    /// `flock` of a lock that this process already holds would block on itself. Tracking
+    /// held locks here lets nested operations (e.g. every record write during `kbs2 rekey`)
+    /// reuse the outermost lock instead.
+    static ref HELD_LOCKS: Mutex<HashMap<PathBuf, HeldLock>> = Mutex::new(HashMap::new());
+}
+
+/// The kinds of locks that can be taken on the store.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LockKind {
+    /// A lock for reading records, which many processes can hold at once.
+    Shared,
+
+    /// A lock for modifying the store, which only one process can hold at a time.
+    Exclusive,
+}
+
+/// A lock on the store that's held by this process, along with the number of
+/// outstanding `StoreLock`s that refer to it.
+struct HeldLock {
+    kind: LockKind,
+    count: usize,
+    _file: File,
+}
+
+/// An advisory lock on a `kbs2` store, released when dropped.
+pub struct StoreLock {
+    path: PathBuf,
+}
+
+impl StoreLock {
+    /// Takes a lock of the given kind on the given store, waiting up to `timeout` for
+    /// any other process holding a conflicting lock to release it.
+    pub fn acquire<P: AsRef<Path>>(store: P, kind: LockKind, timeout: Duration) -> Result<Self> {
+        let store = store.as_ref();
+        fs::create_dir_all(store)?;
+        let path = store.join(LOCK_BASENAME);
+
+        #[allow(clippy::unwrap_used)]
+        let mut held_locks = HELD_LOCKS.lock().unwrap();
+
+        if let Some(held) = held_locks.get_mut(&path) {
+            if held.kind == LockKind::Shared && kind == LockKind::Exclusive {
+                return Err(anyhow!(
+                    "can't upgrade a shared store lock to an exclusive one"
+                ));
+            }
+
+            held.count += 1;
+            return Ok(StoreLock { path });
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        let arg = match kind {
+            LockKind::Shared => FlockArg::LockSharedNonblock,
+            LockKind::Exclusive => FlockArg::LockExclusiveNonblock,
+        };
+
+        let start = Instant::now();
+        loop {
+            match flock(file.as_raw_fd(), arg) {
+                Ok(()) => break,
+                Err(Errno::EWOULDBLOCK) if start.elapsed() < timeout => {
+                    log::debug!("store is locked, retrying");
+                    thread::sleep(LOCK_RETRY_INTERVAL);
+                }
+                Err(Errno::EWOULDBLOCK) => {
+                    let mut holder = String::new();
+                    file.read_to_string(&mut holder)?;
+
+                    return Err(match holder.trim().parse::<u32>() {
+                        Ok(pid) => anyhow!("store is locked by pid {}", pid),
+                        Err(_) => anyhow!("store is locked by another process"),
+                    });
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // Record ourselves as the holder, for the benefit of anybody waiting on us.
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", process::id())?;
+
+        held_locks.insert(
+            path.clone(),
+            HeldLock {
+                kind,
+                count: 1,
+                _file: file,
+            },
+        );
+
+        Ok(StoreLock { path })
+    }
+}
+
+impl Drop for StoreLock {
+    fn drop(&mut self) {
+        #[allow(clippy::unwrap_used)]
+        let mut held_locks = HELD_LOCKS.lock().unwrap();
+
+        if let Some(held) = held_locks.get_mut(&self.path) {
+            held.count -= 1;
+
+            // Dropping the last reference closes the lock file, which releases the lock.
+            if held.count == 0 {
+                held_locks.remove(&self.path);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    /// Takes a lock on the store through a separate open file description, like
+    /// another process would.
+    fn foreign_lock(store: &Path, arg: FlockArg) -> File {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(store.join(LOCK_BASENAME))
+            .unwrap();
+        flock(file.as_raw_fd(), arg).unwrap();
+
+        file
+    }
+
+    #[test]
+    fn test_acquire() {
+        let store = tempdir().unwrap();
+
+        {
+            let _lock = StoreLock::acquire(store.path(), LockKind::Shared, LOCK_TIMEOUT).unwrap();
+            let lock_contents = fs::read_to_string(store.path().join(LOCK_BASENAME)).unwrap();
+            assert_eq!(lock_contents, process::id().to_string());
+        }
+
+        // Locks nest within this process.
+        {
+            let _outer =
+                StoreLock::acquire(store.path(), LockKind::Exclusive, LOCK_TIMEOUT).unwrap();
+            let _inner =
+                StoreLock::acquire(store.path(), LockKind::Exclusive, LOCK_TIMEOUT).unwrap();
+            let _shared = StoreLock::acquire(store.path(), LockKind::Shared, LOCK_TIMEOUT).unwrap();
+        }
+
+        {
+            let _outer = StoreLock::acquire(store.path(), LockKind::Shared, LOCK_TIMEOUT).unwrap();
+            let err = StoreLock::acquire(store.path(), LockKind::Exclusive, LOCK_TIMEOUT)
+                .err()
+                .unwrap();
+            assert_eq!(
+                err.to_string(),
+                "can't upgrade a shared store lock to an exclusive one"
+            );
+        }
+
+        // Every lock above was released, so another process can take the store.
+        foreign_lock(store.path(), FlockArg::LockExclusiveNonblock);
+    }
+
+    #[test]
+    fn test_acquire_contended() {
+        let store = tempdir().unwrap();
+        let timeout = Duration::from_millis(100);
+
+        {
+            let mut foreign = foreign_lock(store.path(), FlockArg::LockExclusiveNonblock);
+            write!(foreign, "1234").unwrap();
+
+            for kind in &[LockKind::Shared, LockKind::Exclusive] {
+                let err = StoreLock::acquire(store.path(), *kind, timeout)
+                    .err()
+                    .unwrap();
+                assert_eq!(err.to_string(), "store is locked by pid 1234");
+            }
+        }
+
+        {
+            let _foreign = foreign_lock(store.path(), FlockArg::LockSharedNonblock);
+
+            // Shared locks coexist, but exclusive ones don't.
+            assert!(StoreLock::acquire(store.path(), LockKind::Shared, timeout).is_ok());
+            assert!(StoreLock::acquire(store.path(), LockKind::Exclusive, timeout).is_err());
+        }
+    }
+}
@@ -13,14 +13,29 @@ pub mod config;
 /// Structures and routines for secret generators.
 pub mod generator;
 
+/// Structures and routines for the encrypted label index.
+pub mod index;
+
 /// Routines for handling user input.
 pub mod input;
 
+/// Structures and routines for validating record labels.
+pub mod label;
+
+/// Structures and routines for locking the `kbs2` store.
+pub mod lock;
+
 /// Structures and routines for creating and managing individual `kbs2` records.
 pub mod record;
 
 /// Structures and routines for creating and managing an active `kbs2` session.
 pub mod session;
 
+/// Structures and routines for using SSH keys as age recipients and identities.
+pub mod ssh;
+
+/// Routines for generating TOTP codes.
+pub mod totp;
+
 /// Reusable utility code for `kbs2`.
 pub mod util;
@@ -1,20 +1,24 @@
+use std::collections::BTreeMap;
+
 use secrecy::Zeroize;
 use serde::{Deserialize, Serialize};
 
 use crate::kbs2::util;
 
 // TODO(ww): Figure out how to generate this from the RecordBody enum below.
-/// The stringified names of record kinds known to `kbs2`.
-pub static RECORD_KINDS: &[&str] = &["login", "environment", "unstructured"];
+/// The stringified names of the built-in record kinds known to `kbs2`.
+///
+/// Users can declare additional kinds in their configuration; see `config::KindConfig`.
+pub static RECORD_KINDS: &[&str] = &["login", "environment", "unstructured", "totp", "ssh-key"];
 
 /// The kinds of fields known to `kbs2`.
 ///
 /// * "Insensitive" fields are accessed with terminal echo and cannot be generated.
 /// * "Sensitive" fields are accessed without terminal echo and can be generated.
 #[derive(Debug)]
-pub enum FieldKind {
-    Insensitive(&'static str),
-    Sensitive(&'static str),
+pub enum FieldKind<'a> {
+    Insensitive(&'a str),
+    Sensitive(&'a str),
 }
 
 /// Represents the envelope of a `kbs2` record.
@@ -28,6 +32,14 @@ pub struct Record {
 
     /// The type contents of the record.
     pub body: RecordBody,
+
+    /// Any additional, kind-independent metadata attached to the record.
+    ///
+    /// NOTE: Records created before metadata was introduced don't have this field,
+    /// and are loaded with empty metadata. Empty metadata isn't serialized, so records
+    /// that don't use it look the same as they did before.
+    #[serde(default, skip_serializing_if = "RecordMetadata::is_empty")]
+    pub metadata: RecordMetadata,
 }
 
 impl Zeroize for Record {
@@ -35,6 +47,86 @@ impl Zeroize for Record {
         self.timestamp.zeroize();
         self.label.zeroize();
         self.body.zeroize();
+        self.metadata.zeroize();
+    }
+}
+
+/// Represents the optional metadata attached to a `kbs2` record.
+#[derive(Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct RecordMetadata {
+    /// Free-form tags associated with the record.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+
+    /// Any URLs associated with the record, e.g. a login page.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub urls: Vec<String>,
+
+    /// Free-form notes associated with the record.
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+
+    /// Any user-defined fields associated with the record, by name.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub fields: BTreeMap<String, CustomField>,
+}
+
+impl RecordMetadata {
+    /// Returns whether or not the metadata contains anything.
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+            && self.urls.is_empty()
+            && self.notes.is_empty()
+            && self.fields.is_empty()
+    }
+}
+
+impl Zeroize for RecordMetadata {
+    fn zeroize(&mut self) {
+        self.tags.zeroize();
+        self.urls.zeroize();
+        self.notes.zeroize();
+
+        // NOTE(ww): Map keys can't be mutated in place, so we take ownership of each
+        // entry in order to zeroize it.
+        for (mut name, mut field) in std::mem::take(&mut self.fields) {
+            name.zeroize();
+            field.zeroize();
+        }
+    }
+}
+
+/// What's displayed in place of a sensitive custom field's value.
+pub const SENSITIVE_MASK: &str = "********";
+
+/// Represents a single user-defined field in a record's metadata.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct CustomField {
+    /// The field's value.
+    pub value: String,
+
+    /// Whether or not the field's value is sensitive, e.g. a recovery code.
+    #[serde(default)]
+    pub sensitive: bool,
+}
+
+impl CustomField {
+    /// Returns the field's value for display: masked if the field is sensitive,
+    /// unless `reveal` is set.
+    pub fn display_value(&self, reveal: bool) -> &str {
+        if self.sensitive && !reveal {
+            SENSITIVE_MASK
+        } else {
+            &self.value
+        }
+    }
+}
+
+impl Zeroize for CustomField {
+    fn zeroize(&mut self) {
+        self.value.zeroize();
+        self.sensitive.zeroize();
     }
 }
 
@@ -45,6 +137,9 @@ pub enum RecordBody {
     Login(LoginFields),
     Environment(EnvironmentFields),
     Unstructured(UnstructuredFields),
+    Totp(TotpFields),
+    SshKey(SshKeyFields),
+    Custom(CustomKindFields),
 }
 
 impl Zeroize for RecordBody {
@@ -53,16 +148,22 @@ impl Zeroize for RecordBody {
             RecordBody::Login(l) => l.zeroize(),
             RecordBody::Environment(e) => e.zeroize(),
             RecordBody::Unstructured(u) => u.zeroize(),
+            RecordBody::Totp(t) => t.zeroize(),
+            RecordBody::SshKey(s) => s.zeroize(),
+            RecordBody::Custom(c) => c.zeroize(),
         };
     }
 }
 
 impl std::fmt::Display for RecordBody {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match *self {
+        match self {
             RecordBody::Login(_) => write!(f, "login"),
             RecordBody::Environment(_) => write!(f, "environment"),
             RecordBody::Unstructured(_) => write!(f, "unstructured"),
+            RecordBody::Totp(_) => write!(f, "totp"),
+            RecordBody::SshKey(_) => write!(f, "ssh-key"),
+            RecordBody::Custom(c) => write!(f, "{}", c.kind),
         }
     }
 }
@@ -114,6 +215,107 @@ impl Zeroize for UnstructuredFields {
     }
 }
 
+/// The hash algorithms that can be used to compute TOTP codes.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub enum TotpAlgorithm {
+    #[default]
+    #[serde(rename = "SHA1")]
+    Sha1,
+    #[serde(rename = "SHA256")]
+    Sha256,
+    #[serde(rename = "SHA512")]
+    Sha512,
+}
+
+impl std::fmt::Display for TotpAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            TotpAlgorithm::Sha1 => write!(f, "SHA1"),
+            TotpAlgorithm::Sha256 => write!(f, "SHA256"),
+            TotpAlgorithm::Sha512 => write!(f, "SHA512"),
+        }
+    }
+}
+
+/// Represents the fields of a TOTP (RFC 6238) record.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct TotpFields {
+    /// The base32-encoded shared secret.
+    pub secret: String,
+
+    /// The service that issued the secret, if known.
+    pub issuer: String,
+
+    /// The account that the secret belongs to, if known.
+    pub account: String,
+
+    /// The number of digits in each generated code.
+    pub digits: u32,
+
+    /// The number of seconds that each generated code is valid for.
+    pub period: u64,
+
+    /// The hash algorithm used to generate codes.
+    pub algorithm: TotpAlgorithm,
+}
+
+impl Default for TotpFields {
+    fn default() -> Self {
+        TotpFields {
+            secret: String::new(),
+            issuer: String::new(),
+            account: String::new(),
+            digits: 6,
+            period: 30,
+            algorithm: Default::default(),
+        }
+    }
+}
+
+impl Zeroize for TotpFields {
+    fn zeroize(&mut self) {
+        self.secret.zeroize();
+        self.issuer.zeroize();
+        self.account.zeroize();
+        self.digits.zeroize();
+        self.period.zeroize();
+    }
+}
+
+/// Represents the fields of an SSH key record.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct SshKeyFields {
+    /// The unencrypted OpenSSH private key.
+    pub private_key: String,
+}
+
+impl Zeroize for SshKeyFields {
+    fn zeroize(&mut self) {
+        self.private_key.zeroize();
+    }
+}
+
+/// Represents the fields of a record whose kind is declared in the user's configuration.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct CustomKindFields {
+    /// The name of the user-defined kind, e.g. `"database"`.
+    pub kind: String,
+
+    /// The record's field values, by field name.
+    pub values: BTreeMap<String, String>,
+}
+
+impl Zeroize for CustomKindFields {
+    fn zeroize(&mut self) {
+        self.kind.zeroize();
+
+        for (mut name, mut value) in std::mem::take(&mut self.values) {
+            name.zeroize();
+            value.zeroize();
+        }
+    }
+}
+
 impl Record {
     /// Creates and returns a new login record with the given label, username, and password.
     pub fn login(label: &str, username: &str, password: &str) -> Record {
@@ -124,6 +326,7 @@ impl Record {
                 username: username.to_owned(),
                 password: password.to_owned(),
             }),
+            metadata: Default::default(),
         }
     }
 
@@ -136,6 +339,7 @@ impl Record {
                 variable: variable.to_owned(),
                 value: value.to_owned(),
             }),
+            metadata: Default::default(),
         }
     }
 
@@ -147,6 +351,44 @@ impl Record {
             body: RecordBody::Unstructured(UnstructuredFields {
                 contents: contents.to_owned(),
             }),
+            metadata: Default::default(),
+        }
+    }
+
+    /// Creates and returns a new record of a user-defined kind, with the given label
+    /// and field values.
+    pub fn custom(label: &str, kind: &str, values: BTreeMap<String, String>) -> Record {
+        Record {
+            timestamp: util::current_timestamp(),
+            label: label.to_owned(),
+            body: RecordBody::Custom(CustomKindFields {
+                kind: kind.to_owned(),
+                values,
+            }),
+            metadata: Default::default(),
+        }
+    }
+
+    /// Creates and returns a new TOTP record with the given label and fields.
+    pub fn totp(label: &str, fields: TotpFields) -> Record {
+        Record {
+            timestamp: util::current_timestamp(),
+            label: label.to_owned(),
+            body: RecordBody::Totp(fields),
+            metadata: Default::default(),
+        }
+    }
+
+    /// Creates and returns a new SSH key record with the given label and unencrypted
+    /// OpenSSH private key.
+    pub fn ssh_key(label: &str, private_key: &str) -> Record {
+        Record {
+            timestamp: util::current_timestamp(),
+            label: label.to_owned(),
+            body: RecordBody::SshKey(SshKeyFields {
+                private_key: private_key.to_owned(),
+            }),
+            metadata: Default::default(),
         }
     }
 }
@@ -195,4 +437,145 @@ mod tests {
             })
         );
     }
+
+    #[test]
+    fn test_totp() {
+        let record = Record::totp(
+            "foo",
+            TotpFields {
+                secret: "bar".into(),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(record.label, "foo");
+        assert_eq!(
+            record.body,
+            RecordBody::Totp(TotpFields {
+                secret: "bar".into(),
+                issuer: "".into(),
+                account: "".into(),
+                digits: 6,
+                period: 30,
+                algorithm: TotpAlgorithm::Sha1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_ssh_key() {
+        let record = Record::ssh_key("foo", "bar");
+
+        assert_eq!(record.label, "foo");
+        assert_eq!(record.body.to_string(), "ssh-key");
+        assert_eq!(
+            record.body,
+            RecordBody::SshKey(SshKeyFields {
+                private_key: "bar".into(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_custom() {
+        let mut values = BTreeMap::new();
+        values.insert("host".into(), "db.example.com".into());
+        values.insert("password".into(), "hunter2".into());
+
+        let record = Record::custom("foo", "database", values);
+
+        assert_eq!(record.label, "foo");
+        assert_eq!(record.body.to_string(), "database");
+
+        match record.body {
+            RecordBody::Custom(c) => {
+                assert_eq!(c.kind, "database");
+                assert_eq!(c.values["host"], "db.example.com");
+                assert_eq!(c.values["password"], "hunter2");
+            }
+            _ => panic!("expected a custom record"),
+        }
+    }
+
+    #[test]
+    fn test_metadata_backwards_compatible() {
+        // Records written before metadata was introduced have no metadata field.
+        let record: Record = serde_json::from_str(
+            r#"{"timestamp":1590363392,"label":"foo","body":{"kind":"Login","fields":{"username":"bar","password":"baz"}}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(record.label, "foo");
+        assert!(record.metadata.is_empty());
+    }
+
+    #[test]
+    fn test_metadata_roundtrip() {
+        let mut record = Record::login("foo", "bar", "baz");
+        record.metadata.tags.push("work".into());
+        record
+            .metadata
+            .urls
+            .push("https://example.com/login".into());
+        record.metadata.notes = "recovery codes are in the safe".into();
+        record.metadata.fields.insert(
+            "pin".into(),
+            CustomField {
+                value: "1234".into(),
+                sensitive: true,
+            },
+        );
+
+        let serialized = serde_json::to_string(&record).unwrap();
+        let deserialized: Record = serde_json::from_str(&serialized).unwrap();
+
+        assert!(!deserialized.metadata.is_empty());
+        assert_eq!(record, deserialized);
+    }
+
+    #[test]
+    fn test_metadata_serialization() {
+        // Records without metadata serialize just like they did before metadata existed...
+        let mut record = Record::login("foo", "bar", "baz");
+        let serialized = serde_json::to_value(&record).unwrap();
+        assert!(serialized.get("metadata").is_none());
+
+        // ...and empty metadata fields are left out.
+        record.metadata.notes = "recovery codes are in the safe".into();
+        let serialized = serde_json::to_value(&record).unwrap();
+        assert_eq!(
+            serialized["metadata"],
+            serde_json::json!({"notes": "recovery codes are in the safe"})
+        );
+    }
+
+    #[test]
+    fn test_custom_field_display_value() {
+        let mut field = CustomField {
+            value: "1234".into(),
+            sensitive: false,
+        };
+        assert_eq!(field.display_value(false), "1234");
+        assert_eq!(field.display_value(true), "1234");
+
+        field.sensitive = true;
+        assert_eq!(field.display_value(false), SENSITIVE_MASK);
+        assert_eq!(field.display_value(true), "1234");
+    }
+
+    #[test]
+    fn test_metadata_zeroize() {
+        let mut record = Record::login("foo", "bar", "baz");
+        record.metadata.tags.push("work".into());
+        record.metadata.fields.insert(
+            "pin".into(),
+            CustomField {
+                value: "1234".into(),
+                sensitive: true,
+            },
+        );
+
+        record.zeroize();
+        assert!(record.metadata.is_empty());
+    }
 }
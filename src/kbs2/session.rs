@@ -1,19 +1,52 @@
 use std::convert::TryFrom;
 use std::fs;
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, Result};
+use secrecy::{ExposeSecret, Secret};
 
 use crate::kbs2::agent::Agent;
-use crate::kbs2::backend::{Backend, RageLib};
+use crate::kbs2::backend::{self, Backend, RecipientStanza};
 use crate::kbs2::config;
+use crate::kbs2::index::Index;
+use crate::kbs2::label::Label;
+use crate::kbs2::lock::{LockKind, StoreLock, LOCK_TIMEOUT};
 use crate::kbs2::record;
+use crate::kbs2::util;
+
+/// The name of the directory, within the store, that holds prior revisions of records.
+pub static HISTORY_DIRNAME: &str = ".history";
+
+/// The name of the directory, within a record's history directory, that holds its revisions.
+///
+/// Revisions live in a hidden directory of their own so that they can't collide with the
+/// history of a record whose label continues the same path, e.g. `work` and `work/1`.
+static REVISIONS_DIRNAME: &str = ".revisions";
+
+/// The basename of the file, within a record's revisions directory, that holds the highest
+/// revision number ever written for that record.
+static LAST_REVISION_BASENAME: &str = ".last-revision";
+
+/// The extension of the file, next to each revision, that records when it was archived.
+static ARCHIVED_EXTENSION: &str = "archived";
+
+/// The basename of a recipients file, which overrides the config's `recipients` for the
+/// records in the folder that contains it (and its subfolders).
+pub static RECIPIENTS_BASENAME: &str = ".recipients";
+
+/// The decrypted contents of a store: every record, every prior revision, and the
+/// encrypted label index (if the store has one).
+pub struct StoreContents {
+    pub records: Vec<Secret<record::Record>>,
+    pub revisions: Vec<(u64, Secret<record::Record>)>,
+    pub index: Option<Index>,
+}
 
 /// Encapsulates the context needed by `kbs2` to interact with records.
 pub struct Session<'a> {
-    /// The `RageLib` backend used to encrypt and decrypt records.
-    pub backend: RageLib,
+    /// The backend used to encrypt and decrypt records.
+    pub backend: Box<dyn Backend>,
 
     /// The configuration that `kbs2` was invoked with.
     pub config: &'a config::Config,
@@ -21,7 +54,7 @@ pub struct Session<'a> {
 
 impl<'a> Session<'a> {
     /// Creates a new session, given a `Config`.
-    fn new(config: &'a config::Config) -> Result<Session> {
+    fn new(config: &'a config::Config) -> Result<Session<'a>> {
         // NOTE(ww): I don't like that we do this here, but I'm not sure where else to put it.
         if config.wrapped && config.agent_autostart {
             Agent::spawn()?;
@@ -31,90 +64,746 @@ impl<'a> Session<'a> {
 
         #[allow(clippy::redundant_field_names)]
         Ok(Session {
-            backend: RageLib::new(config)?,
+            backend: backend::from_config(config)?,
             config: config,
         })
     }
 
-    /// Returns the label of every record available in the store.
-    pub fn record_labels(&self) -> Result<Vec<String>> {
+    /// Takes a lock of the given kind on the store, held until the returned lock is dropped.
+    ///
+    /// Every `Session` operation takes an appropriate lock on its own; this is for callers
+    /// that need several operations to happen without interference, e.g. `kbs2 rekey`.
+    pub fn lock(&self, kind: LockKind) -> Result<StoreLock> {
+        StoreLock::acquire(&self.config.store, kind, LOCK_TIMEOUT)
+    }
+
+    /// Loads the store's encrypted label index.
+    ///
+    /// Only meaningful when the config specifies `encrypted-labels`.
+    pub fn index(&self) -> Result<Index> {
+        Index::load(&self.config.store, self.backend.as_ref())
+    }
+
+    /// Replaces the store's encrypted label index with the given one.
+    ///
+    /// Like `rewrite_record`, this is intended for re-encrypting the store.
+    pub fn rewrite_index(&self, index: &Index) -> Result<()> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        index.save(&self.config.store, self.backend.as_ref())
+    }
+
+    /// Returns the name that the given record is stored under, relative to the store:
+    /// the label itself, or its opaque identifier when labels are encrypted.
+    ///
+    /// Returns `None` if labels are encrypted and the label isn't in the index.
+    fn storage_name(&self, label: &Label) -> Result<Option<String>> {
+        if self.config.encrypted_labels {
+            Ok(self.index()?.id(label).map(Into::into))
+        } else {
+            Ok(Some(label.to_string()))
+        }
+    }
+
+    /// Like `storage_name`, but assigns (and saves) a new opaque identifier for
+    /// labels that aren't in the index yet.
+    fn storage_name_or_insert(&self, label: &Label) -> Result<String> {
+        if !self.config.encrypted_labels {
+            return Ok(label.to_string());
+        }
+
+        let mut index = self.index()?;
+        if let Some(id) = index.id(label) {
+            return Ok(id.into());
+        }
+
+        let id = index.id_or_insert(label).to_string();
+        index.save(&self.config.store, self.backend.as_ref())?;
+
+        Ok(id)
+    }
+
+    /// Returns the label of every record available in the store, including records
+    /// in nested folders.
+    pub fn record_labels(&self) -> Result<Vec<Label>> {
         let store = Path::new(&self.config.store);
 
         if !store.is_dir() {
             return Err(anyhow!("secret store is not a directory"));
         }
 
-        let mut labels = vec![];
-        for entry in fs::read_dir(store)? {
-            let path = entry?.path();
-            if !path.is_file() {
-                log::debug!("skipping non-file in store: {:?}", path);
-                continue;
-            }
+        let _lock = self.lock(LockKind::Shared)?;
 
-            // NOTE(ww): This unwrap is safe, since file_name always returns Some
-            // for non-directories.
-            #[allow(clippy::expect_used)]
-            let label = path
-                .file_name()
-                .expect("impossible: is_file=true for path but file_name=None");
-
-            // NOTE(ww): This one isn't safe, but we don't care. Non-UTF-8 labels aren't supported.
-            labels.push(
-                label
-                    .to_str()
-                    .ok_or_else(|| anyhow!("unrepresentable record label: {:?}", label))?
-                    .into(),
-            );
+        if self.config.encrypted_labels {
+            return Ok(self
+                .index()?
+                .entries()?
+                .into_iter()
+                .filter(|(_, id)| store.join(id).is_file())
+                .map(|(label, _)| label)
+                .collect());
         }
 
+        let mut labels = vec![];
+        collect_labels(store, store, &|path| path.is_file(), &mut labels)?;
+
         Ok(labels)
     }
 
     /// Returns whether or not the store contains a given record.
-    pub fn has_record(&self, label: &str) -> bool {
-        let record_path = Path::new(&self.config.store).join(label);
-
-        record_path.is_file()
+    pub fn has_record(&self, label: &Label) -> bool {
+        match self.storage_name(label) {
+            Ok(Some(name)) => Path::new(&self.config.store).join(name).is_file(),
+            _ => false,
+        }
     }
 
-    /// Retrieves a record from the store by its label.
-    pub fn get_record(&self, label: &str) -> Result<record::Record> {
-        if !self.has_record(label) {
-            return Err(anyhow!("no such record: {}", label));
-        }
+    /// Reads the given record's encrypted contents from the store.
+    pub fn read_record(&self, label: &Label) -> Result<String> {
+        let _lock = self.lock(LockKind::Shared)?;
+
+        let name = self
+            .storage_name(label)?
+            .ok_or_else(|| anyhow!("no such record: {}", label))?;
 
-        let record_path = Path::new(&self.config.store).join(label);
+        let record_path = Path::new(&self.config.store).join(name);
         let record_contents = fs::read_to_string(&record_path).map_err(|e| match e.kind() {
             io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
             _ => e.into(),
         })?;
 
-        match self.backend.decrypt(&record_contents) {
-            Ok(record) => Ok(record),
-            Err(e) => Err(e),
+        Ok(record_contents)
+    }
+
+    /// Retrieves a record from the store by its label.
+    pub fn get_record(&self, label: &Label) -> Result<record::Record> {
+        self.backend.decrypt(&self.read_record(label)?)
+    }
+
+    /// Returns the additional recipients for the given record, from the nearest recipients
+    /// file at or above its folder, or `None` if the config's `recipients` apply.
+    pub fn recipients_for(&self, label: &Label) -> Result<Option<Vec<String>>> {
+        let store = Path::new(&self.config.store);
+
+        for folder in Path::new(label.as_str()).ancestors().skip(1) {
+            let recipients_path = store.join(folder).join(RECIPIENTS_BASENAME);
+
+            let contents = match fs::read_to_string(&recipients_path) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+
+            log::debug!("{} uses recipients from {:?}", label, recipients_path);
+            return Ok(Some(parse_recipients(&contents)));
         }
+
+        Ok(None)
+    }
+
+    /// Returns every recipient that the given record should be encrypted to, starting
+    /// with the config's own public key.
+    pub fn recipient_policy(&self, label: &Label) -> Result<Vec<String>> {
+        let additional = match self.recipients_for(label)? {
+            Some(recipients) => recipients,
+            None => self.config.recipients.clone(),
+        };
+
+        // NOTE: Recipients are compared in their canonical form, so that the same
+        // SSH key with a different comment isn't counted twice.
+        let canonical = |r: &str| {
+            backend::parse_recipient(r)
+                .map(|r| r.to_string())
+                .unwrap_or_else(|_| r.into())
+        };
+
+        let mut recipients = vec![self.config.public_key.clone()];
+        for recipient in additional {
+            if !recipients
+                .iter()
+                .any(|r| canonical(r) == canonical(&recipient))
+            {
+                recipients.push(recipient);
+            }
+        }
+
+        Ok(recipients)
+    }
+
+    /// Returns the recipient stanzas of the given record, as far as they identify the
+    /// recipients that it's actually encrypted to.
+    pub fn record_recipient_stanzas(&self, label: &Label) -> Result<Vec<RecipientStanza>> {
+        backend::recipient_stanzas(&self.read_record(label)?)
     }
 
     /// Adds the given record to the store.
+    ///
+    /// If a record with the same label already exists, it's kept as a prior revision.
     pub fn add_record(&self, record: &record::Record) -> anyhow::Result<()> {
-        let record_path = Path::new(&self.config.store).join(&record.label);
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        self.archive_record(&record.label.parse()?)?;
+        self.rewrite_record(record)
+    }
+
+    /// Writes the given record to the store, **without** keeping any existing record
+    /// with the same label as a prior revision.
+    ///
+    /// This is intended for re-encrypting records; use `add_record` for everything else.
+    pub fn rewrite_record(&self, record: &record::Record) -> Result<()> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        let label: Label = record.label.parse()?;
+        let store = Path::new(&self.config.store);
+        let record_path = store.join(self.storage_name_or_insert(&label)?);
+
+        // A label can't be both a record and a folder of records, e.g. `work` and `work/aws`.
+        // This can't happen with encrypted labels, since they're never used as paths.
+        if !self.config.encrypted_labels {
+            if record_path.is_dir() {
+                return Err(anyhow!(
+                    "label conflicts with an existing folder: {}",
+                    label
+                ));
+            }
 
-        let record_contents = self.backend.encrypt(record)?;
-        std::fs::write(&record_path, &record_contents)?;
+            for parent in record_path.ancestors().skip(1) {
+                if parent == store {
+                    break;
+                }
+
+                if parent.is_file() {
+                    return Err(anyhow!(
+                        "label conflicts with an existing record: {}",
+                        parent.strip_prefix(store)?.display()
+                    ));
+                }
+            }
+
+            if let Some(parent) = record_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+
+        let record_contents = self.encrypt_record(&label, record)?;
+        util::atomic_write(&record_path, &record_contents)?;
 
         Ok(())
     }
 
     /// Deletes a record from the store by label.
-    pub fn delete_record(&self, label: &str) -> Result<()> {
-        let record_path = Path::new(&self.config.store).join(label);
+    ///
+    /// The deleted record is kept as a prior revision, so that it can be restored.
+    pub fn delete_record(&self, label: &Label) -> Result<()> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        let name = self
+            .storage_name(label)?
+            .ok_or_else(|| anyhow!("no such record: {}", label))?;
+        let record_path = Path::new(&self.config.store).join(&name);
+
+        self.archive_record(label)?;
 
         std::fs::remove_file(&record_path).map_err(|e| match e.kind() {
             io::ErrorKind::NotFound => anyhow!("no such record: {}", label),
             _ => e.into(),
+        })?;
+
+        if self.config.encrypted_labels {
+            // Keep the label in the index only while it has a history to restore from.
+            if self.record_revisions(label)?.is_empty() {
+                let mut index = self.index()?;
+                index.remove(label);
+                index.save(&self.config.store, self.backend.as_ref())?;
+
+                // Nothing can refer to the record's history directory anymore, so drop it.
+                let history_dir = self.history_dir(&name);
+                if history_dir.is_dir() {
+                    fs::remove_dir_all(history_dir)?;
+                }
+            }
+        } else {
+            // Clean up any folders that the deletion left empty.
+            let store = Path::new(&self.config.store);
+            for parent in record_path.ancestors().skip(1) {
+                if parent == store || fs::read_dir(parent)?.next().is_some() {
+                    break;
+                }
+
+                fs::remove_dir(parent)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Encrypts the given record to the recipients that apply to its label.
+    fn encrypt_record(&self, label: &Label, record: &record::Record) -> Result<String> {
+        match self.recipients_for(label)? {
+            Some(recipients) => self.backend.encrypt_to(&recipients, record),
+            None => self.backend.encrypt(record),
+        }
+    }
+
+    /// Returns the path to the history directory of the record stored under the given name.
+    fn history_dir(&self, name: &str) -> PathBuf {
+        Path::new(&self.config.store)
+            .join(HISTORY_DIRNAME)
+            .join(name)
+    }
+
+    /// Returns the path to the directory that holds the prior revisions of the record
+    /// stored under the given name.
+    fn revisions_dir(&self, name: &str) -> PathBuf {
+        self.history_dir(name).join(REVISIONS_DIRNAME)
+    }
+
+    /// Returns the label of every record that has prior revisions, including records
+    /// that have since been deleted.
+    pub fn history_labels(&self) -> Result<Vec<Label>> {
+        let history = Path::new(&self.config.store).join(HISTORY_DIRNAME);
+
+        let _lock = self.lock(LockKind::Shared)?;
+
+        if !history.is_dir() {
+            return Ok(vec![]);
+        }
+
+        if self.config.encrypted_labels {
+            return Ok(self
+                .index()?
+                .entries()?
+                .into_iter()
+                .filter(|(_, id)| has_revisions(&history.join(id)))
+                .map(|(label, _)| label)
+                .collect());
+        }
+
+        // NOTE(ww): Unlike in the store itself, a label in the history can be both a record
+        // and a folder, e.g. when `work` was deleted and `work/aws` was created in its place.
+        let mut labels = vec![];
+        collect_labels(&history, &history, &has_revisions, &mut labels)?;
+
+        Ok(labels)
+    }
+
+    /// Returns the revision numbers of the given record's prior revisions, oldest first.
+    pub fn record_revisions(&self, label: &Label) -> Result<Vec<u64>> {
+        let _lock = self.lock(LockKind::Shared)?;
+
+        let revisions_dir = match self.storage_name(label)? {
+            Some(name) => self.revisions_dir(&name),
+            None => return Ok(vec![]),
+        };
+
+        if !revisions_dir.is_dir() {
+            return Ok(vec![]);
+        }
+
+        let mut revisions = vec![];
+        for entry in fs::read_dir(revisions_dir)? {
+            let path = entry?.path();
+            let revision = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .and_then(|n| n.parse::<u64>().ok());
+
+            match revision {
+                Some(revision) if path.is_file() => revisions.push(revision),
+                _ => log::debug!("skipping non-revision in history: {:?}", path),
+            }
+        }
+
+        revisions.sort_unstable();
+
+        Ok(revisions)
+    }
+
+    /// Retrieves a prior revision of a record from the store by its label and revision number.
+    pub fn get_revision(&self, label: &Label, revision: u64) -> Result<record::Record> {
+        let _lock = self.lock(LockKind::Shared)?;
+
+        let no_such_revision = || anyhow!("no such revision: {} (revision {})", label, revision);
+
+        let name = self.storage_name(label)?.ok_or_else(no_such_revision)?;
+        let revision_path = self.revisions_dir(&name).join(revision.to_string());
+        let revision_contents = fs::read_to_string(&revision_path).map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => no_such_revision(),
+            _ => e.into(),
+        })?;
+
+        self.backend.decrypt(&revision_contents)
+    }
+
+    /// Writes the given record as the given revision number, replacing any revision
+    /// already present under that number.
+    ///
+    /// Like `rewrite_record`, this is intended for re-encrypting records. The revision's
+    /// archive time is left as-is, so rewriting a revision doesn't extend its lifetime.
+    pub fn rewrite_revision(&self, revision: u64, record: &record::Record) -> Result<()> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        let label: Label = record.label.parse()?;
+        let name = self.storage_name_or_insert(&label)?;
+        let revisions_dir = self.revisions_dir(&name);
+        fs::create_dir_all(&revisions_dir)?;
+
+        let revision_contents = self.encrypt_record(&label, record)?;
+        util::atomic_write(revisions_dir.join(revision.to_string()), &revision_contents)?;
+
+        Ok(())
+    }
+
+    /// Decrypts every record, prior revision, and (if the store has one) the label index,
+    /// for re-encryption with `rewrite_contents`.
+    pub fn contents(&self) -> Result<StoreContents> {
+        let _lock = self.lock(LockKind::Shared)?;
+
+        let records = self
+            .record_labels()?
+            .iter()
+            .map(|l| self.get_record(l).map(Secret::new))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut revisions = vec![];
+        for label in self.history_labels()? {
+            for revision in self.record_revisions(&label)? {
+                revisions.push((revision, Secret::new(self.get_revision(&label, revision)?)));
+            }
+        }
+
+        let index = if self.config.encrypted_labels {
+            Some(self.index()?)
+        } else {
+            None
+        };
+
+        Ok(StoreContents {
+            records,
+            revisions,
+            index,
         })
     }
+
+    /// Re-encrypts the given store contents with this session's backend, overwriting
+    /// whatever is already stored under the same labels.
+    pub fn rewrite_contents(&self, contents: &StoreContents) -> Result<()> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        // The index goes first, since records with encrypted labels can't be found without it.
+        if let Some(index) = &contents.index {
+            log::debug!("re-encrypting the label index");
+            self.rewrite_index(index)?;
+        }
+
+        for record in &contents.records {
+            log::debug!("re-encrypting {}", record.expose_secret().label);
+            self.rewrite_record(record.expose_secret())?;
+        }
+
+        for (revision, record) in &contents.revisions {
+            log::debug!(
+                "re-encrypting {} revision {}",
+                record.expose_secret().label,
+                revision
+            );
+            self.rewrite_revision(*revision, record.expose_secret())?;
+        }
+
+        Ok(())
+    }
+
+    /// Restores a prior revision of a record, keeping the current record (if any)
+    /// as a prior revision in turn.
+    pub fn restore_record(&self, label: &Label, revision: u64) -> Result<()> {
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        let record = self.get_revision(label, revision)?;
+
+        self.add_record(&record)
+    }
+
+    /// Converts a store with plaintext labels into one with encrypted labels, moving
+    /// every record and prior revision to a file named by an opaque identifier.
+    ///
+    /// Records are moved rather than re-encrypted, and the index is saved before any
+    /// record is moved, so an interrupted conversion can be resumed by running it again.
+    ///
+    /// NOTE: This must be called on a session whose config does **not** specify
+    /// `encrypted-labels`. Updating the config afterwards is the caller's responsibility.
+    pub fn encrypt_labels(&self) -> Result<()> {
+        if self.config.encrypted_labels {
+            return Err(anyhow!("store already has encrypted labels"));
+        }
+
+        let _lock = self.lock(LockKind::Exclusive)?;
+
+        let store = Path::new(&self.config.store);
+
+        // The index is only encrypted to the config's recipients, so anybody added by a
+        // recipients file wouldn't be able to find their records afterwards.
+        if let Some(recipients_path) = find_recipients_file(store)? {
+            return Err(anyhow!(
+                "can't encrypt labels in a store with per-folder recipients: {:?}",
+                recipients_path
+            ));
+        }
+
+        let history = store.join(HISTORY_DIRNAME);
+        let mut index = self.index()?;
+
+        // Records that a previous, interrupted conversion already moved look like
+        // ordinary labels here, so skip anything that's already an identifier.
+        let is_moved = |index: &Index, label: &Label| matches!(index.entries(), Ok(e) if e.iter().any(|(_, id)| *id == label.as_str()));
+
+        let labels: Vec<_> = self
+            .record_labels()?
+            .into_iter()
+            .filter(|l| !is_moved(&index, l))
+            .collect();
+        let history_labels: Vec<_> = self
+            .history_labels()?
+            .into_iter()
+            .filter(|l| !is_moved(&index, l))
+            .collect();
+
+        for label in labels.iter().chain(history_labels.iter()) {
+            index.id_or_insert(label);
+        }
+        index.save(store, self.backend.as_ref())?;
+
+        for label in &labels {
+            #[allow(clippy::unwrap_used)]
+            let id = index.id(label).unwrap();
+            log::debug!("moving {} to {}", label, id);
+
+            fs::rename(store.join(label), store.join(id))?;
+        }
+
+        for label in &history_labels {
+            #[allow(clippy::unwrap_used)]
+            let id = index.id(label).unwrap();
+            log::debug!("moving the history of {} to {}", label, id);
+
+            fs::create_dir_all(history.join(id))?;
+            fs::rename(
+                history.join(label).join(REVISIONS_DIRNAME),
+                history.join(id).join(REVISIONS_DIRNAME),
+            )?;
+        }
+
+        // Finally, clean up the (now empty) folders that the labels used to occupy.
+        remove_empty_dirs(store)?;
+
+        Ok(())
+    }
+
+    /// Keeps the current version of the given record (if there is one) as its newest
+    /// prior revision, subject to the configured history limits.
+    fn archive_record(&self, label: &Label) -> Result<()> {
+        if self.config.history.max_revisions == 0 || !self.has_record(label) {
+            return Ok(());
+        }
+
+        let name = self
+            .storage_name(label)?
+            .ok_or_else(|| anyhow!("no such record: {}", label))?;
+        let revisions_dir = self.revisions_dir(&name);
+        fs::create_dir_all(&revisions_dir)?;
+
+        // NOTE(ww): Pruning can discard every existing revision, so the next revision number
+        // comes from the highest one ever written rather than the highest one still present.
+        let last_revision_path = revisions_dir.join(LAST_REVISION_BASENAME);
+        let last_revision = match fs::read_to_string(&last_revision_path) {
+            Ok(contents) => contents.trim().parse::<u64>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let revision = self
+            .record_revisions(label)?
+            .last()
+            .map_or(last_revision, |r| (*r).max(last_revision))
+            + 1;
+        log::debug!("archiving {} as revision {}", label, revision);
+
+        let revision_path = revisions_dir.join(revision.to_string());
+        util::atomic_write(
+            revision_path.with_extension(ARCHIVED_EXTENSION),
+            util::current_timestamp().to_string(),
+        )?;
+        util::atomic_write(
+            &revision_path,
+            fs::read(Path::new(&self.config.store).join(&name))?,
+        )?;
+        util::atomic_write(&last_revision_path, revision.to_string())?;
+
+        self.prune_history(label)
+    }
+
+    /// Discards any prior revisions of the given record that exceed the configured
+    /// history limits, oldest first.
+    fn prune_history(&self, label: &Label) -> Result<()> {
+        let revisions_dir = match self.storage_name(label)? {
+            Some(name) => self.revisions_dir(&name),
+            None => return Ok(()),
+        };
+        let revisions = self.record_revisions(label)?;
+        let excess = revisions
+            .len()
+            .saturating_sub(self.config.history.max_revisions);
+        let now = util::current_timestamp();
+
+        for (idx, revision) in revisions.iter().enumerate() {
+            let revision_path = revisions_dir.join(revision.to_string());
+            let archived_path = revision_path.with_extension(ARCHIVED_EXTENSION);
+
+            // NOTE(ww): A revision's age is measured from when it was archived, i.e. when it
+            // stopped being the current record, rather than from the record's own timestamp.
+            // Revisions without an archive time never expire by age.
+            let expired = match self.config.history.max_age {
+                Some(max_age) => match fs::read_to_string(&archived_path) {
+                    Ok(archived) => now.saturating_sub(archived.trim().parse::<u64>()?) > max_age,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+                    Err(e) => return Err(e.into()),
+                },
+                None => false,
+            };
+
+            if idx < excess || expired {
+                log::debug!("discarding {} revision {}", label, revision);
+                fs::remove_file(&revision_path)?;
+                if archived_path.is_file() {
+                    fs::remove_file(&archived_path)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Parses the contents of a recipients file: one public key per line, ignoring blank lines
+/// and `#` comments.
+fn parse_recipients(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(Into::into)
+        .collect()
+}
+
+/// Returns the path to a recipients file anywhere within the given store, if there is one.
+///
+/// Hidden directories (like the history directory) are skipped, since recipients files
+/// only apply to the folders that records are stored in.
+pub fn find_recipients_file(store: &Path) -> Result<Option<PathBuf>> {
+    if !store.is_dir() {
+        return Ok(None);
+    }
+
+    let recipients_path = store.join(RECIPIENTS_BASENAME);
+    if recipients_path.is_file() {
+        return Ok(Some(recipients_path));
+    }
+
+    for entry in fs::read_dir(store)? {
+        let path = entry?.path();
+
+        let hidden = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n.starts_with('.'),
+            None => true,
+        };
+
+        if path.is_dir() && !hidden {
+            if let Some(recipients_path) = find_recipients_file(&path)? {
+                return Ok(Some(recipients_path));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Returns whether or not the given path is a history directory containing
+/// at least one revision.
+fn has_revisions(path: &Path) -> bool {
+    let is_revision = |path: &Path| {
+        path.is_file()
+            && matches!(path.file_name().and_then(|n| n.to_str()), Some(n) if n.parse::<u64>().is_ok())
+    };
+
+    let revisions_dir = path.join(REVISIONS_DIRNAME);
+    revisions_dir.is_dir()
+        && fs::read_dir(revisions_dir)
+            .map(|mut e| e.any(|e| matches!(e, Ok(e) if is_revision(&e.path()))))
+            .unwrap_or(false)
+}
+
+/// Recursively removes every empty directory beneath `dir`, but not `dir` itself.
+fn remove_empty_dirs(dir: &Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        if path.is_dir() {
+            remove_empty_dirs(&path)?;
+
+            if fs::read_dir(&path)?.next().is_none() {
+                fs::remove_dir(&path)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Recursively collects the slash-separated label of every path under `dir` that
+/// satisfies `is_label`, relative to `root`.
+///
+/// Hidden files and directories (like the history directory) are skipped, since they
+/// can't be valid labels.
+fn collect_labels(
+    root: &Path,
+    dir: &Path,
+    is_label: &dyn Fn(&Path) -> bool,
+    labels: &mut Vec<Label>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+
+        let hidden = match path.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n.starts_with('.'),
+            None => true,
+        };
+
+        if hidden {
+            log::debug!("skipping hidden file in store: {:?}", path);
+            continue;
+        }
+
+        if is_label(&path) {
+            // NOTE(ww): Non-UTF-8 labels aren't supported.
+            let label = path
+                .strip_prefix(root)?
+                .iter()
+                .map(|c| {
+                    c.to_str()
+                        .ok_or_else(|| anyhow!("unrepresentable record label: {:?}", path))
+                })
+                .collect::<Result<Vec<_>>>()?
+                .join("/");
+
+            labels.push(label.parse()?);
+        }
+
+        if path.is_dir() {
+            collect_labels(root, &path, is_label, labels)?;
+        } else if !path.is_file() {
+            log::debug!("skipping non-file in store: {:?}", path);
+        }
+    }
+
+    Ok(())
 }
 
 impl<'a> TryFrom<&'a config::Config> for Session<'a> {
@@ -127,9 +816,14 @@ impl<'a> TryFrom<&'a config::Config> for Session<'a> {
 
 #[cfg(test)]
 mod tests {
+    use std::thread;
+    use std::time::Duration;
+
     use tempfile::{tempdir, TempDir};
 
     use super::*;
+    use crate::kbs2::backend::{Identities, RageLib};
+    use crate::kbs2::index::INDEX_BASENAME;
 
     // NOTE: We pass store in here instead of creating it for lifetime reasons:
     // the temp dir is unlinked when its TempDir object is destructed, so we need
@@ -143,30 +837,68 @@ mod tests {
             keyfile: "not a real private key file".into(),
             agent_autostart: false,
             wrapped: false,
+            agent_key_export: false,
+            agent_key_ttl: None,
+            agent_key_idle_timeout: None,
+            agent_key_confirm: false,
+            agent_max_unwrap_attempts: 10,
+            recipients: vec![],
+            backend: Default::default(),
+            age_binary: "age".into(),
             store: store.path().to_str().unwrap().into(),
+            encrypted_labels: false,
             pinentry: Default::default(),
             pre_hook: None,
             post_hook: None,
             error_hook: None,
             reentrant_hooks: false,
             generators: vec![config::GeneratorConfig::Internal(Default::default())],
+            kinds: vec![],
+            history: Default::default(),
             commands: Default::default(),
         }
     }
 
-    fn dummy_session(config: &config::Config) -> Session {
-        let backend = {
-            let key = age::x25519::Identity::generate();
-
-            RageLib {
-                pubkey: key.to_public(),
-                identities: vec![key.into()],
-            }
-        };
+    fn label(label: &str) -> Label {
+        Label::new(label).unwrap()
+    }
 
+    fn key_session(
+        config: &config::Config,
+        key: age::x25519::Identity,
+        recipients: Vec<backend::Recipient>,
+    ) -> Session<'_> {
         Session {
-            backend,
-            config: &config,
+            backend: Box::new(RageLib {
+                pubkey: key.to_public().into(),
+                recipients,
+                identities: Identities::Local(vec![key.into()]),
+            }),
+            config,
+        }
+    }
+
+    fn dummy_session(config: &config::Config) -> Session<'_> {
+        key_session(config, age::x25519::Identity::generate(), vec![])
+    }
+
+    /// A backend that "encrypts" by reversing its input, for testing `Session` on its own.
+    struct MockBackend;
+
+    impl Backend for MockBackend {
+        fn encrypt_bytes(&self, plaintext: &[u8]) -> Result<String> {
+            Ok(String::from_utf8(plaintext.to_vec())?
+                .chars()
+                .rev()
+                .collect())
+        }
+
+        fn encrypt_bytes_to(&self, _recipients: &[String], plaintext: &[u8]) -> Result<String> {
+            self.encrypt_bytes(plaintext)
+        }
+
+        fn decrypt_bytes(&self, encrypted: &str) -> Result<Vec<u8>> {
+            Ok(encrypted.chars().rev().collect::<String>().into_bytes())
         }
     }
 
@@ -181,7 +913,7 @@ mod tests {
             let config = dummy_config(&store);
             let session = dummy_session(&config);
 
-            assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
+            assert_eq!(session.record_labels().unwrap(), Vec::<Label>::new());
         }
 
         {
@@ -195,6 +927,87 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_record_labels_nested() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+
+        for label in &["foo", "work/aws/prod", "work/aws/staging", "work/email"] {
+            session
+                .add_record(&record::Record::login(label, "bar", "baz"))
+                .unwrap();
+        }
+
+        let mut labels = session.record_labels().unwrap();
+        labels.sort();
+        assert_eq!(
+            labels,
+            vec!["foo", "work/aws/prod", "work/aws/staging", "work/email"]
+        );
+
+        assert!(session.has_record(&label("work/aws/prod")));
+        assert!(!session.has_record(&label("work/aws")));
+        assert_eq!(
+            session.get_record(&label("work/email")).unwrap().label,
+            "work/email"
+        );
+
+        // A label can't be both a record and a folder.
+        let err = session
+            .add_record(&record::Record::login("work/aws", "bar", "baz"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "label conflicts with an existing folder: work/aws"
+        );
+
+        let err = session
+            .add_record(&record::Record::login("foo/bar", "bar", "baz"))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "label conflicts with an existing record: foo"
+        );
+
+        // Deleting the last record in a folder removes the folder.
+        session.delete_record(&label("work/aws/prod")).unwrap();
+        session.delete_record(&label("work/aws/staging")).unwrap();
+        assert!(!store.path().join("work/aws").exists());
+        assert!(store.path().join("work").is_dir());
+
+        // ...and its history is still available.
+        let mut labels = session.history_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, vec!["work/aws/prod", "work/aws/staging"]);
+        assert_eq!(
+            session.record_revisions(&label("work/aws/prod")).unwrap(),
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn test_add_record_invalid_label() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+
+        for bad in &[
+            "../escape",
+            "/tmp/escape",
+            "work/../../escape",
+            ".history/foo",
+        ] {
+            let record = record::Record::login(bad, "bar", "baz");
+            assert!(session.add_record(&record).is_err());
+        }
+
+        // Nothing was written, inside the store or out of it.
+        assert_eq!(session.record_labels().unwrap(), Vec::<Label>::new());
+        assert!(!store.path().join("../escape").exists());
+        assert!(!store.path().join(".history/foo").exists());
+    }
+
     #[test]
     fn test_has_record() {
         {
@@ -204,7 +1017,7 @@ mod tests {
             let record = record::Record::login("foo", "bar", "baz");
 
             session.add_record(&record).unwrap();
-            assert!(session.has_record("foo"));
+            assert!(session.has_record(&label("foo")));
         }
 
         {
@@ -212,7 +1025,7 @@ mod tests {
             let config = dummy_config(&store);
             let session = dummy_session(&config);
 
-            assert!(!session.has_record("does-not-exist"));
+            assert!(!session.has_record(&label("does-not-exist")));
         }
     }
 
@@ -226,7 +1039,7 @@ mod tests {
 
             session.add_record(&record).unwrap();
 
-            let retrieved_record = session.get_record("foo").unwrap();
+            let retrieved_record = session.get_record(&label("foo")).unwrap();
 
             assert_eq!(record, retrieved_record);
         }
@@ -236,7 +1049,7 @@ mod tests {
             let config = dummy_config(&store);
             let session = dummy_session(&config);
 
-            let err = session.get_record("foo").unwrap_err();
+            let err = session.get_record(&label("foo")).unwrap_err();
             assert_eq!(err.to_string(), "no such record: foo");
         }
     }
@@ -257,19 +1070,46 @@ mod tests {
             // NOTE: record_labels() returns labels in a platform dependent order,
             // which is why we don't compared against a fixed-order vec here or below.
             assert_eq!(session.record_labels().unwrap().len(), 2);
-            assert!(session.record_labels().unwrap().contains(&"foo".into()));
-            assert!(session.record_labels().unwrap().contains(&"a".into()));
+            assert!(session.record_labels().unwrap().contains(&label("foo")));
+            assert!(session.record_labels().unwrap().contains(&label("a")));
 
             // Overwrite foo; still only two records.
             let record3 = record::Record::login("foo", "quux", "zap");
             session.add_record(&record3).unwrap();
 
             assert_eq!(session.record_labels().unwrap().len(), 2);
-            assert!(session.record_labels().unwrap().contains(&"foo".into()));
-            assert!(session.record_labels().unwrap().contains(&"a".into()));
+            assert!(session.record_labels().unwrap().contains(&label("foo")));
+            assert!(session.record_labels().unwrap().contains(&label("a")));
         }
     }
 
+    #[test]
+    fn test_mock_backend() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = Session {
+            backend: Box::new(MockBackend),
+            config: &config,
+        };
+
+        let record = record::Record::login("foo", "bar", "baz");
+        session.add_record(&record).unwrap();
+
+        // The session stores whatever the backend produces...
+        let stored = fs::read_to_string(store.path().join("foo")).unwrap();
+        assert_eq!(
+            stored,
+            serde_json::to_string(&record)
+                .unwrap()
+                .chars()
+                .rev()
+                .collect::<String>()
+        );
+
+        // ...and reads it back through the backend.
+        assert_eq!(session.get_record(&label("foo")).unwrap(), record);
+    }
+
     #[test]
     fn test_delete_record() {
         {
@@ -280,9 +1120,9 @@ mod tests {
 
             session.add_record(&record).unwrap();
 
-            assert!(session.delete_record("foo").is_ok());
-            assert!(!session.has_record("foo"));
-            assert_eq!(session.record_labels().unwrap(), Vec::<String>::new());
+            assert!(session.delete_record(&label("foo")).is_ok());
+            assert!(!session.has_record(&label("foo")));
+            assert_eq!(session.record_labels().unwrap(), Vec::<Label>::new());
         }
 
         {
@@ -296,7 +1136,7 @@ mod tests {
             let record2 = record::Record::login("a", "b", "c");
             session.add_record(&record2).unwrap();
 
-            assert!(session.delete_record("foo").is_ok());
+            assert!(session.delete_record(&label("foo")).is_ok());
             assert_eq!(session.record_labels().unwrap(), vec!["a"]);
         }
 
@@ -305,8 +1145,514 @@ mod tests {
             let config = dummy_config(&store);
             let session = dummy_session(&config);
 
-            let err = session.delete_record("does-not-exist").unwrap_err();
+            let err = session.delete_record(&label("does-not-exist")).unwrap_err();
             assert_eq!(err.to_string(), "no such record: does-not-exist");
         }
     }
+
+    #[test]
+    fn test_record_revisions() {
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+
+            assert_eq!(
+                session.record_revisions(&label("foo")).unwrap(),
+                Vec::<u64>::new()
+            );
+            assert_eq!(session.history_labels().unwrap(), Vec::<Label>::new());
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+            assert_eq!(
+                session.record_revisions(&label("foo")).unwrap(),
+                Vec::<u64>::new()
+            );
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "quux"))
+                .unwrap();
+            session
+                .add_record(&record::Record::login("foo", "bar", "zap"))
+                .unwrap();
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![1, 2]);
+            assert_eq!(session.history_labels().unwrap(), vec!["foo"]);
+
+            // The history directory doesn't show up as a record.
+            assert_eq!(session.record_labels().unwrap(), vec!["foo"]);
+
+            match session.get_revision(&label("foo"), 1).unwrap().body {
+                record::RecordBody::Login(l) => assert_eq!(l.password, "baz"),
+                _ => panic!("expected a login record"),
+            }
+
+            let err = session.get_revision(&label("foo"), 3).unwrap_err();
+            assert_eq!(err.to_string(), "no such revision: foo (revision 3)");
+
+            // Deleting a record keeps it as a revision.
+            session.delete_record(&label("foo")).unwrap();
+            assert_eq!(
+                session.record_revisions(&label("foo")).unwrap(),
+                vec![1, 2, 3]
+            );
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut config = dummy_config(&store);
+            config.history.max_revisions = 2;
+            let session = dummy_session(&config);
+
+            for password in &["a", "b", "c", "d"] {
+                session
+                    .add_record(&record::Record::login("foo", "bar", password))
+                    .unwrap();
+            }
+
+            // Only the newest revisions are kept, and revision numbers are never reused.
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![2, 3]);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut config = dummy_config(&store);
+            config.history.max_age = Some(0);
+            let session = dummy_session(&config);
+
+            for password in &["a", "b", "c"] {
+                session
+                    .add_record(&record::Record::login("foo", "bar", password))
+                    .unwrap();
+            }
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![1, 2]);
+
+            // Once every revision has aged out, numbering still doesn't start over.
+            thread::sleep(Duration::from_millis(1100));
+            session
+                .add_record(&record::Record::login("foo", "bar", "d"))
+                .unwrap();
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![3]);
+            assert!(session.history_labels().unwrap().contains(&label("foo")));
+
+            thread::sleep(Duration::from_millis(1100));
+            session.delete_record(&label("foo")).unwrap();
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![4]);
+
+            thread::sleep(Duration::from_millis(1100));
+            session
+                .add_record(&record::Record::login("foo", "bar", "e"))
+                .unwrap();
+            session
+                .add_record(&record::Record::login("foo", "bar", "f"))
+                .unwrap();
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![5]);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut config = dummy_config(&store);
+            config.history.max_age = Some(60);
+            let session = dummy_session(&config);
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "a"))
+                .unwrap();
+            session
+                .add_record(&record::Record::login("foo", "bar", "b"))
+                .unwrap();
+
+            // Backdate revision 1's archive time, then rewrite it, as `rekey` would.
+            fs::write(session.revisions_dir("foo").join("1.archived"), "0").unwrap();
+            let revision = session.get_revision(&label("foo"), 1).unwrap();
+            session.rewrite_revision(1, &revision).unwrap();
+
+            // Rewriting doesn't reset the archive time, so revision 1 still ages out.
+            session
+                .add_record(&record::Record::login("foo", "bar", "c"))
+                .unwrap();
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![2]);
+            assert!(!session.revisions_dir("foo").join("1.archived").exists());
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+
+            // `work` and `work/1` have distinct histories, even though the latter's label
+            // looks like one of the former's revision numbers.
+            session
+                .add_record(&record::Record::login("work", "bar", "a"))
+                .unwrap();
+            session.delete_record(&label("work")).unwrap();
+            session
+                .add_record(&record::Record::login("work/1", "bar", "b"))
+                .unwrap();
+            session
+                .add_record(&record::Record::login("work/1", "bar", "c"))
+                .unwrap();
+
+            assert_eq!(session.record_revisions(&label("work")).unwrap(), vec![1]);
+            assert_eq!(session.record_revisions(&label("work/1")).unwrap(), vec![1]);
+            assert_eq!(
+                session.history_labels().unwrap(),
+                vec![label("work"), label("work/1")]
+            );
+
+            match session.get_revision(&label("work"), 1).unwrap().body {
+                record::RecordBody::Login(l) => assert_eq!(l.password, "a"),
+                _ => panic!("expected a login record"),
+            }
+            match session.get_revision(&label("work/1"), 1).unwrap().body {
+                record::RecordBody::Login(l) => assert_eq!(l.password, "b"),
+                _ => panic!("expected a login record"),
+            }
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let mut config = dummy_config(&store);
+            config.history.max_revisions = 0;
+            let session = dummy_session(&config);
+
+            session
+                .add_record(&record::Record::login("foo", "bar", "baz"))
+                .unwrap();
+            session
+                .add_record(&record::Record::login("foo", "bar", "quux"))
+                .unwrap();
+
+            assert_eq!(
+                session.record_revisions(&label("foo")).unwrap(),
+                Vec::<u64>::new()
+            );
+        }
+    }
+
+    #[test]
+    fn test_restore_record() {
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+
+            let record1 = record::Record::login("foo", "bar", "baz");
+            session.add_record(&record1).unwrap();
+            let record2 = record::Record::login("foo", "bar", "quux");
+            session.add_record(&record2).unwrap();
+
+            session.restore_record(&label("foo"), 1).unwrap();
+            assert_eq!(session.get_record(&label("foo")).unwrap(), record1);
+
+            // The record that was replaced by the restore is itself kept.
+            assert_eq!(session.record_revisions(&label("foo")).unwrap(), vec![1, 2]);
+            assert_eq!(session.get_revision(&label("foo"), 2).unwrap(), record2);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+
+            let record = record::Record::login("foo", "bar", "baz");
+            session.add_record(&record).unwrap();
+            session.delete_record(&label("foo")).unwrap();
+
+            session.restore_record(&label("foo"), 1).unwrap();
+            assert_eq!(session.get_record(&label("foo")).unwrap(), record);
+        }
+
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+
+            let err = session.restore_record(&label("foo"), 1).unwrap_err();
+            assert_eq!(err.to_string(), "no such revision: foo (revision 1)");
+        }
+    }
+
+    #[test]
+    fn test_encrypted_labels() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.encrypted_labels = true;
+        let session = dummy_session(&config);
+
+        let record = record::Record::login("work/aws/prod", "bar", "baz");
+        session.add_record(&record).unwrap();
+        session
+            .add_record(&record::Record::login("pets.com", "bar", "baz"))
+            .unwrap();
+
+        let mut labels = session.record_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, vec!["pets.com", "work/aws/prod"]);
+
+        assert!(session.has_record(&label("work/aws/prod")));
+        assert!(!session.has_record(&label("work/aws")));
+        assert_eq!(session.get_record(&label("work/aws/prod")).unwrap(), record);
+
+        // Nothing in the store is named after a label.
+        let id = session
+            .index()
+            .unwrap()
+            .id(&label("work/aws/prod"))
+            .unwrap()
+            .to_string();
+        for entry in fs::read_dir(store.path()).unwrap() {
+            let name = entry.unwrap().file_name().into_string().unwrap();
+            assert!(!name.contains("work") && !name.contains("pets"));
+        }
+        assert!(store.path().join(&id).is_file());
+
+        // History works as usual, and survives deletion.
+        let record2 = record::Record::login("work/aws/prod", "bar", "quux");
+        session.add_record(&record2).unwrap();
+        session.delete_record(&label("work/aws/prod")).unwrap();
+        assert!(!session.has_record(&label("work/aws/prod")));
+        assert_eq!(session.record_labels().unwrap(), vec!["pets.com"]);
+        assert_eq!(session.history_labels().unwrap(), vec!["work/aws/prod"]);
+        assert!(store.path().join(HISTORY_DIRNAME).join(&id).is_dir());
+
+        session.restore_record(&label("work/aws/prod"), 1).unwrap();
+        assert_eq!(session.get_record(&label("work/aws/prod")).unwrap(), record);
+
+        // Deleting a record without history drops it from the index entirely.
+        let mut config = config.clone();
+        config.history.max_revisions = 0;
+        let session = Session {
+            backend: session.backend,
+            config: &config,
+        };
+        session.delete_record(&label("pets.com")).unwrap();
+        assert_eq!(session.index().unwrap().id(&label("pets.com")), None);
+
+        let err = session.get_record(&label("pets.com")).unwrap_err();
+        assert_eq!(err.to_string(), "no such record: pets.com");
+    }
+
+    #[test]
+    fn test_encrypt_labels() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+
+        let record = record::Record::login("work/aws/prod", "bar", "baz");
+        session.add_record(&record).unwrap();
+        session
+            .add_record(&record::Record::login("work/aws/prod", "bar", "quux"))
+            .unwrap();
+        session
+            .add_record(&record::Record::login("foo", "bar", "baz"))
+            .unwrap();
+
+        session.encrypt_labels().unwrap();
+
+        // The plaintext labels are gone from the store...
+        assert!(!store.path().join("work").exists());
+        assert!(!store.path().join("foo").exists());
+        assert!(!store.path().join(HISTORY_DIRNAME).join("work").exists());
+
+        // ...but everything is still reachable once the config specifies encrypted labels.
+        let mut config = config.clone();
+        config.encrypted_labels = true;
+        let session = Session {
+            backend: session.backend,
+            config: &config,
+        };
+
+        let mut labels = session.record_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, vec!["foo", "work/aws/prod"]);
+        assert_eq!(
+            session.record_revisions(&label("work/aws/prod")).unwrap(),
+            vec![1]
+        );
+        assert_eq!(
+            session.get_revision(&label("work/aws/prod"), 1).unwrap(),
+            record
+        );
+
+        let err = session.encrypt_labels().unwrap_err();
+        assert_eq!(err.to_string(), "store already has encrypted labels");
+
+        {
+            let store = tempdir().unwrap();
+            let config = dummy_config(&store);
+            let session = dummy_session(&config);
+
+            session
+                .add_record(&record::Record::login("ops/db", "bar", "baz"))
+                .unwrap();
+            fs::write(store.path().join("ops").join(RECIPIENTS_BASENAME), "").unwrap();
+
+            let err = session.encrypt_labels().unwrap_err();
+            assert!(err
+                .to_string()
+                .starts_with("can't encrypt labels in a store with per-folder recipients"));
+
+            // Nothing was moved.
+            assert!(store.path().join("ops/db").is_file());
+            assert!(!store.path().join(INDEX_BASENAME).exists());
+        }
+    }
+
+    #[test]
+    fn test_rewrite_contents() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.encrypted_labels = true;
+        let key = age::x25519::Identity::generate();
+        let session = key_session(&config, key.clone(), vec![]);
+
+        let record = record::Record::login("work/aws/prod", "bar", "baz");
+        session.add_record(&record).unwrap();
+        session
+            .add_record(&record::Record::login("work/aws/prod", "quux", "zap"))
+            .unwrap();
+        session
+            .add_record(&record::Record::login("pets.com", "bar", "baz"))
+            .unwrap();
+
+        let contents = session.contents().unwrap();
+        assert_eq!(contents.records.len(), 2);
+        assert_eq!(contents.revisions.len(), 1);
+        assert!(contents.index.is_some());
+
+        // Re-encrypt the store to its original key, plus another recipient.
+        let other = age::x25519::Identity::generate();
+        let session = key_session(&config, key, vec![other.to_public().into()]);
+        session.rewrite_contents(&contents).unwrap();
+
+        // The other recipient can now read every record, revision, and the index...
+        let other_session = key_session(&config, other, vec![]);
+
+        for session in &[&session, &other_session] {
+            let mut labels = session.record_labels().unwrap();
+            labels.sort();
+            assert_eq!(labels, vec!["pets.com", "work/aws/prod"]);
+            assert_eq!(
+                session.get_revision(&label("work/aws/prod"), 1).unwrap(),
+                record
+            );
+        }
+
+        // ...but a stranger can't.
+        let stranger_session = dummy_session(&config);
+        assert!(stranger_session.record_labels().is_err());
+    }
+
+    #[test]
+    fn test_recipients_for() {
+        let store = tempdir().unwrap();
+        let mut config = dummy_config(&store);
+        config.recipients = vec!["age1default".into()];
+        let session = dummy_session(&config);
+
+        // Without any recipients files, the config's recipients apply.
+        assert_eq!(session.recipients_for(&label("ops/db")).unwrap(), None);
+        assert_eq!(
+            session.recipient_policy(&label("ops/db")).unwrap(),
+            vec!["not a real public key", "age1default"]
+        );
+
+        fs::create_dir_all(store.path().join("ops/aws")).unwrap();
+        fs::write(
+            store.path().join("ops").join(RECIPIENTS_BASENAME),
+            "# the ops team\nage1alice\n\n  age1bob  \nnot a real public key\n",
+        )
+        .unwrap();
+
+        // The nearest recipients file applies, including to nested folders...
+        for l in &["ops/db", "ops/aws/prod"] {
+            assert_eq!(
+                session.recipients_for(&label(l)).unwrap(),
+                Some(vec![
+                    "age1alice".into(),
+                    "age1bob".into(),
+                    "not a real public key".into()
+                ])
+            );
+            assert_eq!(
+                session.recipient_policy(&label(l)).unwrap(),
+                vec!["not a real public key", "age1alice", "age1bob"]
+            );
+        }
+
+        // ...but not to records outside of its folder.
+        assert_eq!(session.recipients_for(&label("personal/db")).unwrap(), None);
+        assert_eq!(session.recipients_for(&label("opsdb")).unwrap(), None);
+
+        // A recipients file in a nested folder overrides one further up, and an empty
+        // recipients file means "only the config's own key".
+        fs::write(store.path().join("ops/aws").join(RECIPIENTS_BASENAME), "").unwrap();
+        assert_eq!(
+            session.recipients_for(&label("ops/aws/prod")).unwrap(),
+            Some(vec![])
+        );
+        assert_eq!(
+            session.recipient_policy(&label("ops/aws/prod")).unwrap(),
+            vec!["not a real public key"]
+        );
+
+        // A recipients file at the top of the store applies to everything else.
+        fs::write(store.path().join(RECIPIENTS_BASENAME), "age1carol\n").unwrap();
+        assert_eq!(
+            session.recipients_for(&label("personal/db")).unwrap(),
+            Some(vec!["age1carol".into()])
+        );
+    }
+
+    #[test]
+    fn test_add_record_recipients_file() {
+        let store = tempdir().unwrap();
+        let config = dummy_config(&store);
+        let session = dummy_session(&config);
+
+        let other = age::x25519::Identity::generate();
+        fs::create_dir_all(store.path().join("ops")).unwrap();
+        fs::write(
+            store.path().join("ops").join(RECIPIENTS_BASENAME),
+            other.to_public().to_string(),
+        )
+        .unwrap();
+        let other_session = key_session(&config, other, vec![]);
+
+        let ops_record = record::Record::login("ops/db", "bar", "baz");
+        let personal_record = record::Record::login("personal/db", "bar", "baz");
+        session.add_record(&ops_record).unwrap();
+        session.add_record(&personal_record).unwrap();
+
+        // The recipients file isn't mistaken for a record.
+        let mut labels = session.record_labels().unwrap();
+        labels.sort();
+        assert_eq!(labels, vec!["ops/db", "personal/db"]);
+
+        // Records under `ops/` are encrypted to the other recipient as well...
+        assert_eq!(
+            session
+                .record_recipient_stanzas(&label("ops/db"))
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            other_session.get_record(&label("ops/db")).unwrap(),
+            ops_record
+        );
+        assert_eq!(session.get_record(&label("ops/db")).unwrap(), ops_record);
+
+        // ...but other records aren't.
+        assert_eq!(
+            session
+                .record_recipient_stanzas(&label("personal/db"))
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(other_session.get_record(&label("personal/db")).is_err());
+    }
 }
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac, NewMac};
+use percent_encoding::percent_decode_str;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+
+use crate::kbs2::record::{TotpAlgorithm, TotpFields};
+
+/// The URI scheme and type prefix used by `otpauth://` TOTP URIs.
+static OTPAUTH_TOTP_PREFIX: &str = "otpauth://totp/";
+
+/// Decodes the given base32 TOTP secret, ignoring case, whitespace, and padding.
+fn decode_secret(secret: &str) -> Result<Vec<u8>> {
+    let secret = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .collect::<String>();
+
+    match base32::decode(base32::Alphabet::RFC4648 { padding: false }, &secret) {
+        Some(key) if !key.is_empty() => Ok(key),
+        _ => Err(anyhow!("invalid TOTP secret: expected a base32 string")),
+    }
+}
+
+/// Computes the RFC 4226 HMAC for the given key and counter.
+fn hmac(algorithm: TotpAlgorithm, key: &[u8], counter: u64) -> Result<Vec<u8>> {
+    // NOTE(ww): The HMAC constructors only fail on invalid key lengths,
+    // which HMAC itself doesn't have.
+    let hmac_err = |_| anyhow!("impossible: HMAC rejected key length");
+
+    let digest = match algorithm {
+        TotpAlgorithm::Sha1 => {
+            let mut mac = Hmac::<Sha1>::new_from_slice(key).map_err(hmac_err)?;
+            mac.update(&counter.to_be_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        TotpAlgorithm::Sha256 => {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).map_err(hmac_err)?;
+            mac.update(&counter.to_be_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+        TotpAlgorithm::Sha512 => {
+            let mut mac = Hmac::<Sha512>::new_from_slice(key).map_err(hmac_err)?;
+            mac.update(&counter.to_be_bytes());
+            mac.finalize().into_bytes().to_vec()
+        }
+    };
+
+    Ok(digest)
+}
+
+/// Computes the RFC 6238 TOTP code for the given fields at the given time,
+/// as seconds since the Unix epoch.
+pub fn code(totp: &TotpFields, timestamp: u64) -> Result<String> {
+    if !(6..=8).contains(&totp.digits) {
+        return Err(anyhow!(
+            "invalid TOTP digit count: {} (expected 6 to 8)",
+            totp.digits
+        ));
+    }
+
+    if totp.period == 0 {
+        return Err(anyhow!("invalid TOTP period: must be nonzero"));
+    }
+
+    let key = decode_secret(&totp.secret)?;
+    let digest = hmac(totp.algorithm, &key, timestamp / totp.period)?;
+
+    // Dynamic truncation, per RFC 4226 § 5.3.
+    let offset = (digest[digest.len() - 1] & 0xf) as usize;
+    let binary = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    Ok(format!(
+        "{:0width$}",
+        binary % 10u32.pow(totp.digits),
+        width = totp.digits as usize
+    ))
+}
+
+/// Parses an `otpauth://totp/...` URI into its TOTP fields.
+///
+/// Parameters that are absent from the URI take their RFC 6238 defaults.
+pub fn parse_uri(uri: &str) -> Result<TotpFields> {
+    let rest = uri
+        .strip_prefix(OTPAUTH_TOTP_PREFIX)
+        .ok_or_else(|| anyhow!("not an otpauth://totp/ URI"))?;
+
+    let (label, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let label = percent_decode_str(label).decode_utf8()?;
+
+    // The label is either "issuer:account" or just "account".
+    let (label_issuer, account) = match label.split_once(':') {
+        Some((issuer, account)) => (issuer.trim(), account.trim()),
+        None => ("", label.trim()),
+    };
+
+    let mut totp = TotpFields {
+        issuer: label_issuer.into(),
+        account: account.into(),
+        ..Default::default()
+    };
+
+    let mut secret = None;
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        let value = percent_decode_str(value).decode_utf8()?;
+
+        match key {
+            "secret" => secret = Some(value.into_owned()),
+            // NOTE(ww): The issuer parameter is preferred to the label prefix, when both
+            // are present.
+            "issuer" => totp.issuer = value.into_owned(),
+            "algorithm" => {
+                totp.algorithm = match value.to_uppercase().as_str() {
+                    "SHA1" => TotpAlgorithm::Sha1,
+                    "SHA256" => TotpAlgorithm::Sha256,
+                    "SHA512" => TotpAlgorithm::Sha512,
+                    _ => return Err(anyhow!("unsupported TOTP algorithm: {}", value)),
+                }
+            }
+            "digits" => {
+                totp.digits = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid TOTP digit count: {}", value))?
+            }
+            "period" => {
+                totp.period = value
+                    .parse()
+                    .map_err(|_| anyhow!("invalid TOTP period: {}", value))?
+            }
+            _ => log::debug!("ignoring unknown otpauth parameter: {}", key),
+        }
+    }
+
+    totp.secret = secret.ok_or_else(|| anyhow!("otpauth URI is missing a secret"))?;
+
+    // Fail early on secrets and parameters that can't produce a code.
+    code(&totp, 0)?;
+
+    Ok(totp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn totp(secret: &str, algorithm: TotpAlgorithm) -> TotpFields {
+        TotpFields {
+            secret: secret.into(),
+            digits: 8,
+            algorithm,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_code() {
+        // Test vectors from RFC 6238, Appendix B.
+        let sha1 = totp("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", TotpAlgorithm::Sha1);
+        let sha256 = totp(
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZA====",
+            TotpAlgorithm::Sha256,
+        );
+        let sha512 = totp(
+            "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ\
+             GEZDGNBVGY3TQOJQGEZDGNA=",
+            TotpAlgorithm::Sha512,
+        );
+
+        for (time, sha1_code, sha256_code, sha512_code) in &[
+            (59, "94287082", "46119246", "90693936"),
+            (1111111109, "07081804", "68084774", "25091201"),
+            (1111111111, "14050471", "67062674", "99943326"),
+            (1234567890, "89005924", "91819424", "93441116"),
+            (2000000000, "69279037", "90698825", "38618901"),
+            (20000000000, "65353130", "77737706", "47863826"),
+        ] {
+            assert_eq!(code(&sha1, *time).unwrap(), *sha1_code);
+            assert_eq!(code(&sha256, *time).unwrap(), *sha256_code);
+            assert_eq!(code(&sha512, *time).unwrap(), *sha512_code);
+        }
+
+        {
+            let totp = TotpFields {
+                secret: "gezd gnbv gy3t qojq gezd gnbv gy3t qojq".into(),
+                ..Default::default()
+            };
+            assert_eq!(code(&totp, 59).unwrap(), "287082");
+        }
+
+        {
+            let totp = totp("not base32!", TotpAlgorithm::Sha1);
+            let err = code(&totp, 59).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid TOTP secret: expected a base32 string"
+            );
+        }
+
+        {
+            let mut totp = totp("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ", TotpAlgorithm::Sha1);
+            totp.digits = 10;
+            let err = code(&totp, 59).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "invalid TOTP digit count: 10 (expected 6 to 8)"
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_uri() {
+        {
+            let totp = parse_uri(
+                "otpauth://totp/ACME%20Co:john.doe@email.com?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ\
+                 &issuer=ACME%20Co&algorithm=SHA256&digits=8&period=60",
+            )
+            .unwrap();
+
+            assert_eq!(totp.secret, "HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ");
+            assert_eq!(totp.issuer, "ACME Co");
+            assert_eq!(totp.account, "john.doe@email.com");
+            assert_eq!(totp.algorithm, TotpAlgorithm::Sha256);
+            assert_eq!(totp.digits, 8);
+            assert_eq!(totp.period, 60);
+        }
+
+        {
+            let totp =
+                parse_uri("otpauth://totp/alice@example.com?secret=GEZDGNBVGY3TQOJQ").unwrap();
+
+            assert_eq!(totp.issuer, "");
+            assert_eq!(totp.account, "alice@example.com");
+            assert_eq!(totp.algorithm, TotpAlgorithm::Sha1);
+            assert_eq!(totp.digits, 6);
+            assert_eq!(totp.period, 30);
+        }
+
+        {
+            let err = parse_uri("otpauth://hotp/foo?secret=GEZDGNBVGY3TQOJQ").unwrap_err();
+            assert_eq!(err.to_string(), "not an otpauth://totp/ URI");
+        }
+
+        {
+            let err = parse_uri("otpauth://totp/foo?issuer=bar").unwrap_err();
+            assert_eq!(err.to_string(), "otpauth URI is missing a secret");
+        }
+
+        {
+            let err =
+                parse_uri("otpauth://totp/foo?secret=GEZDGNBVGY3TQOJQ&algorithm=MD5").unwrap_err();
+            assert_eq!(err.to_string(), "unsupported TOTP algorithm: MD5");
+        }
+    }
+}
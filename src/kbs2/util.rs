@@ -1,6 +1,6 @@
 use std::ffi::OsStr;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{self, File};
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
 use std::time::{SystemTime, UNIX_EPOCH};
@@ -113,11 +113,52 @@ pub fn read_guarded<P: AsRef<Path>>(path: P, limit: u64) -> Result<Vec<u8>> {
     Ok(buf)
 }
 
+/// Write the given contents to the given path atomically, replacing any file already
+/// there.
+///
+/// The contents are written and synced to a temporary file in the same directory,
+/// which is then renamed over `path`. The directory itself is synced afterwards, so
+/// that the rename survives a crash. A failure at any point leaves any previous file
+/// at `path` intact.
+pub fn atomic_write<P: AsRef<Path>, C: AsRef<[u8]>>(path: P, contents: C) -> Result<()> {
+    let path = path.as_ref();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(contents.as_ref())?;
+    file.as_file().sync_all()?;
+    file.persist(path).map_err(|e| e.error)?;
+
+    File::open(dir)?.sync_all()?;
+
+    Ok(())
+}
+
+/// Recursively copy the contents of the directory at `src` into `dst`, creating `dst`
+/// and any subdirectories as needed.
+pub fn copy_dir<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> Result<()> {
+    fs::create_dir_all(&dst)?;
+
+    for entry in fs::read_dir(&src)? {
+        let entry = entry?;
+        let dst = dst.as_ref().join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            copy_dir(entry.path(), dst)?;
+        } else {
+            fs::copy(entry.path(), dst)?;
+        }
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
-    use std::io::Write;
-
-    use tempfile::NamedTempFile;
+    use tempfile::{tempdir, NamedTempFile};
 
     use super::*;
 
@@ -219,7 +260,7 @@ mod tests {
     fn test_read_guarded() {
         {
             let mut small = NamedTempFile::new().unwrap();
-            small.write(b"test").unwrap();
+            small.write_all(b"test").unwrap();
             small.flush().unwrap();
 
             let contents = read_guarded(small.path(), 1024);
@@ -229,10 +270,47 @@ mod tests {
 
         {
             let mut toobig = NamedTempFile::new().unwrap();
-            toobig.write(b"slightlytoobig").unwrap();
+            toobig.write_all(b"slightlytoobig").unwrap();
             toobig.flush().unwrap();
 
             assert!(read_guarded(toobig.path(), 10).is_err());
         }
     }
+
+    #[test]
+    fn test_atomic_write() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("file");
+
+        atomic_write(&path, "first").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first");
+
+        atomic_write(&path, "second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+
+        // No temporary files are left behind.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        // Writing into a missing directory fails without creating anything.
+        assert!(atomic_write(dir.path().join("missing/file"), "third").is_err());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_copy_dir() {
+        let src = tempdir().unwrap();
+        fs::create_dir_all(src.path().join("a/b")).unwrap();
+        fs::write(src.path().join("top"), "top").unwrap();
+        fs::write(src.path().join("a/b/nested"), "nested").unwrap();
+
+        let dst = tempdir().unwrap();
+        let dst = dst.path().join("copy");
+        copy_dir(src.path(), &dst).unwrap();
+
+        assert_eq!(fs::read_to_string(dst.join("top")).unwrap(), "top");
+        assert_eq!(
+            fs::read_to_string(dst.join("a/b/nested")).unwrap(),
+            "nested"
+        );
+    }
 }
@@ -45,6 +45,7 @@ fn app() -> App<'static> {
                 .subcommand(
                     App::new("flush")
                         .about("remove all unwrapped keys from the running agent")
+                        .alias("lock")
                         .arg(
                             Arg::new("quit")
                                 .about("quit the agent after flushing")
@@ -52,13 +53,58 @@ fn app() -> App<'static> {
                                 .long("quit"),
                         ),
                 )
+                .subcommand(
+                    App::new("log").about("show the agent's log of requests and their outcomes"),
+                )
                 .subcommand(
                     App::new("query")
                         .about("ask the current agent whether it has the current config's key"),
                 )
+                .subcommand(
+                    App::new("ssh-add")
+                        .about("serve SSH keys from ssh-key records to SSH clients")
+                        .arg(
+                            Arg::new("label")
+                                .about("the labels of the ssh-key records to serve")
+                                .index(1)
+                                .required(true)
+                                .multiple_values(true),
+                        ),
+                )
+                .subcommand(
+                    App::new("status")
+                        .about("list the keys held by the running agent")
+                        .arg(
+                            Arg::new("json")
+                                .about("list in JSON format")
+                                .short('j')
+                                .long("json"),
+                        ),
+                )
                 .subcommand(
                     App::new("unwrap")
-                        .about("unwrap the current config's key in the running agent"),
+                        .about("unwrap the current config's key in the running agent")
+                        .arg(
+                            Arg::new("ttl")
+                                .about("forget the key after this many seconds")
+                                .short('t')
+                                .long("ttl")
+                                .value_name("SECS")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::new("idle-timeout")
+                                .about("forget the key after this many seconds without use")
+                                .short('i')
+                                .long("idle-timeout")
+                                .value_name("SECS")
+                                .takes_value(true),
+                        )
+                        .arg(
+                            Arg::new("confirm")
+                                .about("ask before each process uses the key")
+                                .long("confirm"),
+                        ),
                 ),
         )
         .subcommand(
@@ -96,11 +142,10 @@ fn app() -> App<'static> {
                 )
                 .arg(
                     Arg::new("kind")
-                        .about("the kind of record to create")
+                        .about("the kind of record to create (built-in or from the config)")
                         .short('k')
                         .long("kind")
                         .takes_value(true)
-                        .possible_values(kbs2::record::RECORD_KINDS)
                         .default_value("login"),
                 )
                 .arg(
@@ -133,19 +178,30 @@ fn app() -> App<'static> {
         .subcommand(
             App::new("list")
                 .about("list records")
+                .arg(
+                    Arg::new("folder")
+                        .about("list only records in this folder, e.g. `work/`")
+                        .index(1),
+                )
                 .arg(
                     Arg::new("details")
                         .about("print (non-field) details for each record")
                         .short('d')
                         .long("details"),
                 )
+                .arg(
+                    Arg::new("tree")
+                        .about("print records as a tree of folders")
+                        .short('T')
+                        .long("tree")
+                        .conflicts_with("details"),
+                )
                 .arg(
                     Arg::new("kind")
                         .about("list only records of this kind")
                         .short('k')
                         .long("kind")
-                        .takes_value(true)
-                        .possible_values(kbs2::record::RECORD_KINDS),
+                        .takes_value(true),
                 ),
         )
         .subcommand(
@@ -157,6 +213,35 @@ fn app() -> App<'static> {
                     .multiple_values(true),
             ),
         )
+        .subcommand(
+            App::new("history")
+                .about("list the prior revisions of a record")
+                .arg(
+                    Arg::new("label")
+                        .about("the record's label")
+                        .index(1)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            App::new("restore")
+                .about("restore a prior revision of a record")
+                .arg(
+                    Arg::new("label")
+                        .about("the record's label")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("rev")
+                        .about("the revision to restore, as listed by `kbs2 history`")
+                        .short('r')
+                        .long("rev")
+                        .value_name("N")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
         .subcommand(
             App::new("dump")
                 .about("dump one or more records")
@@ -172,6 +257,12 @@ fn app() -> App<'static> {
                         .about("dump in JSON format (JSONL when multiple)")
                         .short('j')
                         .long("json"),
+                )
+                .arg(
+                    Arg::new("reveal")
+                        .about("show sensitive metadata fields instead of masking them")
+                        .short('r')
+                        .long("reveal"),
                 ),
         )
         .subcommand(
@@ -190,6 +281,22 @@ fn app() -> App<'static> {
                         .long("clipboard"),
                 ),
         )
+        .subcommand(
+            App::new("otp")
+                .about("get the current code for a TOTP record")
+                .arg(
+                    Arg::new("label")
+                        .about("the record's label")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("clipboard")
+                        .about("copy the code to the clipboard")
+                        .short('c')
+                        .long("clipboard"),
+                ),
+        )
         .subcommand(
             App::new("env")
                 .about("get an environment record")
@@ -265,6 +372,42 @@ fn app() -> App<'static> {
                         .long("no-backup"),
                 ),
         )
+        .subcommand(
+            App::new("encrypt-labels")
+                .about("convert the store to encrypted labels, hiding which records it contains"),
+        )
+        .subcommand(
+            App::new("recipients")
+                .about("manage the public keys that records are encrypted to")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    App::new("add")
+                        .about("add recipients and re-encrypt the store to them")
+                        .arg(
+                            Arg::new("pubkey")
+                                .about("the public keys to add")
+                                .index(1)
+                                .required(true)
+                                .multiple_values(true),
+                        ),
+                )
+                .subcommand(
+                    App::new("remove")
+                        .about("remove recipients and re-encrypt the store without them")
+                        .arg(
+                            Arg::new("pubkey")
+                                .about("the public keys to remove")
+                                .index(1)
+                                .required(true)
+                                .multiple_values(true),
+                        ),
+                )
+                .subcommand(App::new("list").about("list the store's recipients"))
+                .subcommand(App::new("check").about(
+                    "report records that aren't encrypted to their expected recipients \
+                         (age keys are checked by count only)",
+                )),
+        )
 }
 
 fn run(matches: &ArgMatches, config: &kbs2::config::Config) -> Result<()> {
@@ -288,13 +431,18 @@ fn run(matches: &ArgMatches, config: &kbs2::config::Config) -> Result<()> {
         Some(("new", matches)) => kbs2::command::new(matches, config)?,
         Some(("list", matches)) => kbs2::command::list(matches, config)?,
         Some(("rm", matches)) => kbs2::command::rm(matches, config)?,
+        Some(("history", matches)) => kbs2::command::history(matches, config)?,
+        Some(("restore", matches)) => kbs2::command::restore(matches, config)?,
         Some(("dump", matches)) => kbs2::command::dump(matches, config)?,
         Some(("pass", matches)) => kbs2::command::pass(matches, config)?,
+        Some(("otp", matches)) => kbs2::command::otp(matches, config)?,
         Some(("env", matches)) => kbs2::command::env(matches, config)?,
         Some(("edit", matches)) => kbs2::command::edit(matches, config)?,
         Some(("generate", matches)) => kbs2::command::generate(matches, config)?,
         Some(("rewrap", matches)) => kbs2::command::rewrap(matches, config)?,
         Some(("rekey", matches)) => kbs2::command::rekey(matches, config)?,
+        Some(("encrypt-labels", matches)) => kbs2::command::encrypt_labels(matches, config)?,
+        Some(("recipients", matches)) => kbs2::command::recipients(matches, config)?,
         Some((cmd, matches)) => {
             let cmd = format!("kbs2-{}", cmd);
 
@@ -350,7 +498,7 @@ fn main() -> Result<()> {
     #[allow(clippy::unwrap_used)]
     let config_dir = Path::new(matches.value_of_os("config-dir").unwrap());
     log::debug!("config dir: {:?}", config_dir);
-    std::fs::create_dir_all(&config_dir)?;
+    std::fs::create_dir_all(config_dir)?;
 
     // There are two special cases that are not handled in `run`:
     //
@@ -366,7 +514,7 @@ fn main() -> Result<()> {
     }
 
     // Everything else (i.e., all other subcommands) go through here.
-    let config = kbs2::config::load(&config_dir)?;
+    let config = kbs2::config::load(config_dir)?;
     match run(&matches, &config) {
         Ok(()) => Ok(()),
         Err(e) => {